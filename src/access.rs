@@ -0,0 +1,437 @@
+#![warn(clippy::pedantic)]
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Extension, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use clap::{Subcommand, ValueEnum};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+use crate::{
+    database::{get_active_api_key, get_api_keys, insert_api_key, revoke_api_key},
+    limits::ClientIp,
+    tasks::log_error,
+};
+
+const WINDOW: Duration = Duration::from_mins(1);
+const KEY_PREFIX: &str = "wvw_";
+// Expired windows are only swept once the map grows past this
+const MAX_TRACKED_CONSUMERS: usize = 10_000;
+// A stream token only has to open the connection, reconnects fetch a new one
+const STREAM_TOKEN_TTL: Duration = Duration::from_mins(1);
+// Browsers open these without custom headers (EventSource, WebSocket), so they take a token instead
+const STREAM_PATHS: [&str; 2] = ["/data/events", "/ws"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AccessMode {
+    /// Anyone may read, no limits
+    Public,
+    /// Anyone may read, clients without a key share a per-IP quota
+    Anonymous,
+    /// Every request needs an API key. Browsers can't send it to /data/events and /ws,
+    /// they exchange it at /data/token for a token that is valid for a minute.
+    Key,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommand {
+    /// Issue a new key, it is printed once and only its hash is stored
+    Create {
+        /// Name of the consumer the key belongs to
+        name: String,
+        /// Requests per minute, unlimited if omitted
+        #[arg(long)]
+        quota: Option<u32>,
+    },
+    /// Revoke the key of a consumer
+    Revoke { name: String },
+    /// List issued keys
+    List,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+enum Consumer {
+    Key(i64),
+    Ip(IpAddr),
+}
+
+// The key a request was let in with, for handlers that act on behalf of it
+#[derive(Clone, Copy)]
+pub struct KeyHolder {
+    id: i64,
+    quota: Option<u32>,
+}
+
+struct StreamToken {
+    holder: KeyHolder,
+    expires: Instant,
+}
+
+struct QuotaWindow {
+    started: Instant,
+    used: u32,
+}
+
+pub struct Access {
+    mode: AccessMode,
+    pool: SqlitePool,
+    anonymous_quota: u32,
+    windows: Mutex<HashMap<Consumer, QuotaWindow>>,
+    tokens: Mutex<HashMap<String, StreamToken>>,
+}
+
+impl Access {
+    pub fn new(mode: AccessMode, pool: SqlitePool, anonymous_quota: u32) -> Self {
+        Self {
+            mode,
+            pool,
+            anonymous_quota,
+            windows: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn issue_token(&self, holder: KeyHolder) -> String {
+        let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, t| t.expires > now);
+        tokens.insert(
+            token.clone(),
+            StreamToken {
+                holder,
+                expires: now + STREAM_TOKEN_TTL,
+            },
+        );
+        drop(tokens);
+        token
+    }
+
+    fn redeem_token(&self, token: &str) -> Option<KeyHolder> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .get(token)
+            .filter(|t| t.expires > Instant::now())
+            .map(|t| t.holder)
+    }
+
+    // Ok holds the remaining requests, Err the time until the window resets
+    fn take(&self, consumer: Consumer, quota: u32) -> Result<u32, Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() > MAX_TRACKED_CONSUMERS {
+            windows.retain(|_, w| now.duration_since(w.started) < WINDOW);
+        }
+
+        let window = windows.entry(consumer).or_insert(QuotaWindow {
+            started: now,
+            used: 0,
+        });
+        if now.duration_since(window.started) >= WINDOW {
+            window.started = now;
+            window.used = 0;
+        }
+
+        let result = if window.used >= quota {
+            Err(WINDOW.saturating_sub(now.duration_since(window.started)))
+        } else {
+            window.used += 1;
+            Ok(quota - window.used)
+        };
+        drop(windows);
+        result
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("{KEY_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes))
+}
+
+// Accepts `X-API-Key: <key>` or `Authorization: Bearer <key>`
+fn provided_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+}
+
+// `?token=` on the stream routes, anywhere else a key has to be sent as a header
+fn stream_token(req: &Request) -> Option<&str> {
+    if !STREAM_PATHS.contains(&req.uri().path()) {
+        return None;
+    }
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .filter(|token| !token.is_empty())
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+    )
+        .into_response()
+}
+
+pub async fn check_access(
+    State(access): State<Arc<Access>>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if access.mode == AccessMode::Public {
+        return next.run(req).await;
+    }
+
+    let mut response = enforce(&access, ip, req, next).await;
+    // What a client gets depends on its key, shared caches must not hand it to anyone else
+    make_private(response.headers_mut());
+    response
+}
+
+async fn enforce(access: &Access, ip: IpAddr, mut req: Request, next: Next) -> Response {
+    let holder = match provided_key(req.headers()) {
+        Some(key) => match get_active_api_key(&access.pool, &hash_key(key)).await {
+            Ok(Some(api_key)) => Some(KeyHolder {
+                id: api_key.id,
+                quota: api_key.quota_per_minute,
+            }),
+            Ok(None) => return unauthorized(),
+            Err(err) => {
+                log_error(err);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+        None => stream_token(&req).and_then(|token| access.redeem_token(token)),
+    };
+
+    let (consumer, quota) = match holder {
+        Some(holder) => {
+            req.extensions_mut().insert(holder);
+            (Consumer::Key(holder.id), holder.quota)
+        }
+        None if access.mode == AccessMode::Key => return unauthorized(),
+        None => (Consumer::Ip(ip), Some(access.anonymous_quota)),
+    };
+
+    let Some(quota) = quota else {
+        return next.run(req).await;
+    };
+
+    match access.take(consumer, quota) {
+        Ok(remaining) => {
+            let mut response = next.run(req).await;
+            let headers = response.headers_mut();
+            headers.insert("x-ratelimit-limit", HeaderValue::from(quota));
+            headers.insert("x-ratelimit-remaining", HeaderValue::from(remaining));
+            response
+        }
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [
+                (
+                    header::RETRY_AFTER,
+                    retry_after.as_secs().max(1).to_string(),
+                ),
+                (
+                    HeaderName::from_static("x-ratelimit-limit"),
+                    quota.to_string(),
+                ),
+            ],
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct IssuedToken {
+    token: String,
+    expires_in: u64,
+}
+
+// Exchanges the key sent as a header for a token the stream routes accept as `?token=`
+pub async fn issue_stream_token(
+    State(access): State<Arc<Access>>,
+    holder: Option<Extension<KeyHolder>>,
+) -> Response {
+    // Without a key there is nothing to stand in for, anonymous clients open streams directly
+    let Some(Extension(holder)) = holder else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(IssuedToken {
+            token: access.issue_token(holder),
+            expires_in: STREAM_TOKEN_TTL.as_secs(),
+        }),
+    )
+        .into_response()
+}
+
+// Turns Cache-Control into a private one, responses that are never stored stay as they are
+fn make_private(headers: &mut HeaderMap) {
+    let Some(cache_control) = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return;
+    };
+
+    let directives: Vec<&str> = cache_control
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty() && !directive.eq_ignore_ascii_case("public"))
+        .collect();
+    if directives.iter().any(|directive| {
+        directive.eq_ignore_ascii_case("private") || directive.eq_ignore_ascii_case("no-store")
+    }) {
+        return;
+    }
+
+    let private = std::iter::once("private")
+        .chain(directives)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&private) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+}
+
+pub async fn run_key_command(pool: &SqlitePool, command: KeyCommand) -> Result<(), sqlx::Error> {
+    match command {
+        KeyCommand::Create { name, quota } => {
+            let key = generate_key();
+            insert_api_key(pool, &name, &hash_key(&key), quota).await?;
+            println!("{key}");
+        }
+        KeyCommand::Revoke { name } => {
+            if revoke_api_key(pool, &name).await? {
+                println!("Revoked key of {name}");
+            } else {
+                println!("No active key named {name}");
+            }
+        }
+        KeyCommand::List => {
+            for key in get_api_keys(pool).await? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    key.name,
+                    key.quota_per_minute
+                        .map_or_else(|| "unlimited".to_string(), |q| format!("{q}/min")),
+                    key.created_at,
+                    key.revoked_at
+                        .map_or_else(|| "active".to_string(), |t| format!("revoked {t}")),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn private(cache_control: Option<&str>) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        if let Some(cache_control) = cache_control {
+            headers.insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_str(cache_control).unwrap(),
+            );
+        }
+        make_private(&mut headers);
+        headers
+            .get(header::CACHE_CONTROL)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn shared_cache_directives_become_private() {
+        assert_eq!(
+            private(Some("max-age=2, stale-while-revalidate=30")).as_deref(),
+            Some("private, max-age=2, stale-while-revalidate=30")
+        );
+        assert_eq!(
+            private(Some("public, max-age=86400")).as_deref(),
+            Some("private, max-age=86400")
+        );
+        assert_eq!(private(Some("no-store")).as_deref(), Some("no-store"));
+        assert_eq!(
+            private(Some("private, max-age=5")).as_deref(),
+            Some("private, max-age=5")
+        );
+        assert_eq!(private(None), None);
+    }
+
+    #[tokio::test]
+    async fn stream_tokens_stand_in_for_a_key_until_they_expire() {
+        let access = Access::new(AccessMode::Key, crate::database::test_pool().await, 1);
+        let holder = KeyHolder {
+            id: 7,
+            quota: Some(30),
+        };
+
+        let token = access.issue_token(holder);
+        let redeemed = access.redeem_token(&token).unwrap();
+        assert_eq!((redeemed.id, redeemed.quota), (7, Some(30)));
+        assert!(access.redeem_token("made-up").is_none());
+
+        access
+            .tokens
+            .lock()
+            .unwrap()
+            .get_mut(&token)
+            .unwrap()
+            .expires = Instant::now();
+        assert!(access.redeem_token(&token).is_none());
+        // Expired tokens are swept when the next one is issued
+        access.issue_token(holder);
+        assert!(!access.tokens.lock().unwrap().contains_key(&token));
+    }
+
+    #[test]
+    fn tokens_are_only_read_on_stream_routes() {
+        let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        assert_eq!(
+            stream_token(&request("/data/events?token=abc")),
+            Some("abc")
+        );
+        assert_eq!(stream_token(&request("/ws?x=1&token=abc")), Some("abc"));
+        assert_eq!(stream_token(&request("/data/?token=abc")), None);
+        assert_eq!(stream_token(&request("/api/v1/teams?token=abc")), None);
+        assert_eq!(stream_token(&request("/data/events?token=")), None);
+        assert_eq!(stream_token(&request("/data/events")), None);
+    }
+}
@@ -0,0 +1,809 @@
+#![warn(clippy::pedantic)]
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use axum::{
+    Json, Router,
+    extract::{FromRef, Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio::sync::RwLock;
+use utoipa::{IntoParams, OpenApi};
+
+use crate::{
+    data::{
+        ArchivedMatch, ArchivedMatchDetail, Data, GuildFetchStatus, GuildMove, GuildName,
+        GuildProfile, GuildSearchResult, MatchData, MatchTeam, Prediction, RosterGuild, Snapshot,
+        TeamMembership, TeamRoster, TeamSummary, Tier, TierMatch, TierSummary,
+    },
+    database::{
+        get_archived_match, get_archived_matches, get_archived_roster, get_guild, get_guild_claims,
+        get_guild_move_counts, get_guild_moves, get_guild_names, get_guild_status, get_guild_team,
+        get_guild_team_history, get_last_guild_update, get_match, get_snapshots, get_team_roster,
+    },
+    emblem::render_guild_emblem,
+    search::{SearchIndex, team_locations},
+    tasks::{TEAM_NAMES, log_error},
+};
+
+const DEFAULT_POINTS: usize = 200;
+const MAX_POINTS: usize = 2000;
+const DEFAULT_HISTORY_LIMIT: u32 = 50;
+const MAX_HISTORY_LIMIT: u32 = 500;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+const DEFAULT_EVENT_LIMIT: u32 = 200;
+const MAX_EVENT_LIMIT: u32 = 2000;
+
+#[derive(Clone)]
+struct ApiState {
+    pool: SqlitePool,
+    cache: Arc<RwLock<Data>>,
+    search: Arc<RwLock<SearchIndex>>,
+}
+
+impl FromRef<ApiState> for SqlitePool {
+    fn from_ref(state: &ApiState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<ApiState> for Arc<RwLock<Data>> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.cache.clone()
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "WvW Overview API", version = "1"),
+    paths(
+        current_matches,
+        current_match,
+        tiers,
+        tier_summary,
+        tier_snapshots,
+        teams,
+        team_roster,
+        guild_search,
+        guild_profile,
+        guild_team_history,
+        guild_names,
+        guild_status,
+        guild_emblem,
+        guild_moves,
+        history_matches,
+        history_match,
+        predictions,
+    ),
+    tags(
+        (name = "matches", description = "Running matches with their scores"),
+        (name = "tiers", description = "Tiers and the teams playing in them"),
+        (name = "teams", description = "Teams and their guild rosters"),
+        (name = "guilds", description = "Guild profiles, history and emblems"),
+        (name = "history", description = "Archived matches of previous weeks"),
+        (name = "events", description = "Guild team changes"),
+        (name = "predictions", description = "Projected movement after reset"),
+    )
+)]
+struct ApiDoc;
+
+fn routes() -> Router<ApiState> {
+    Router::new()
+        .route("/matches", get(current_matches))
+        .route("/matches/{tier}", get(current_match))
+        .route("/tiers", get(tiers))
+        .route("/tiers/{tier}", get(tier_summary))
+        .route("/tiers/{tier}/snapshots", get(tier_snapshots))
+        .route("/history/matches", get(history_matches))
+        .route("/history/matches/{week_key}", get(history_match))
+        .route("/guilds/{id}/teams", get(guild_team_history))
+        .route("/guilds/{id}/names", get(guild_names))
+        .route("/guilds/{id}/status", get(guild_status))
+        .route("/events/guild-moves", get(guild_moves))
+        .route("/guilds/search", get(guild_search))
+        .route("/guilds/{id}", get(guild_profile))
+        .route("/predictions", get(predictions))
+        .route("/guilds/{id}/emblem.svg", get(guild_emblem))
+        .route("/teams", get(teams))
+        .route("/teams/{team_id}", get(team_roster))
+}
+
+pub fn router(
+    pool: SqlitePool,
+    cache: Arc<RwLock<Data>>,
+    search: Arc<RwLock<SearchIndex>>,
+) -> Router<()> {
+    Router::new()
+        .nest(
+            "/api/v1",
+            routes().route("/openapi.json", get(Json(ApiDoc::openapi()))),
+        )
+        // Unversioned paths predate v1, keep them for existing consumers
+        .nest("/api", routes())
+        .with_state(ApiState {
+            pool,
+            cache,
+            search,
+        })
+}
+
+fn internal_error<E: fmt::Debug>(err: E) -> Response {
+    log_error(err);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+// Timestamps are stored as RFC 3339 in UTC, bring query values into the same shape
+fn normalize_time(value: Option<&str>) -> Result<Option<String>, StatusCode> {
+    value
+        .map(|v| {
+            v.parse::<DateTime<Utc>>()
+                .map(|t| t.to_rfc3339())
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        })
+        .transpose()
+}
+
+fn match_teams(m: &MatchData) -> Vec<MatchTeam> {
+    [("red", &m.red), ("green", &m.green), ("blue", &m.blue)]
+        .into_iter()
+        .map(|(color, team)| MatchTeam {
+            color: color.to_string(),
+            team_id: team.team_id.clone(),
+            team_name: team.team_name.clone(),
+            victory_points: team.victory_points.parse().unwrap_or(0),
+            points_per_tick: team.points_per_tick,
+            skirmish_score: team.skirmish_score,
+            projected_score: team.projected_score,
+            projected_placement: team.projected_placement,
+            guild_count: team.guilds.values().map(Vec::len).sum(),
+        })
+        .collect()
+}
+
+fn match_id(tier: usize) -> String {
+    Tier::from_number(tier).map_or_else(String::new, Tier::as_id)
+}
+
+fn to_tier_match(index: usize, m: &MatchData) -> TierMatch {
+    TierMatch {
+        tier: index + 1,
+        match_id: match_id(index + 1),
+        skirmishes_remaining: m.skirmishes_remaining,
+        teams: match_teams(m),
+    }
+}
+
+fn to_tier_summary(index: usize, m: &MatchData) -> TierSummary {
+    TierSummary {
+        tier: index + 1,
+        match_id: match_id(index + 1),
+        teams: match_teams(m)
+            .into_iter()
+            .map(|team| TeamSummary {
+                team_id: team.team_id,
+                team_name: team.team_name,
+                tier: Some(index + 1),
+                color: Some(team.color),
+            })
+            .collect(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/matches",
+    tag = "matches",
+    responses((status = 200, body = [TierMatch]))
+)]
+async fn current_matches(State(cache): State<Arc<RwLock<Data>>>) -> Response {
+    let data = cache.read().await;
+    let matches: Vec<TierMatch> = data
+        .matches
+        .iter()
+        .map(|(&index, m)| to_tier_match(index, m))
+        .collect();
+    drop(data);
+    Json(matches).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/matches/{tier}",
+    tag = "matches",
+    params(("tier" = usize, Path, description = "Tier number, 1 is the highest")),
+    responses((status = 200, body = TierMatch), (status = 404))
+)]
+async fn current_match(
+    State(cache): State<Arc<RwLock<Data>>>,
+    Path(tier): Path<usize>,
+) -> Response {
+    let data = cache.read().await;
+    let Some(m) = tier
+        .checked_sub(1)
+        .and_then(|index| data.matches.get(&index))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    Json(to_tier_match(tier - 1, m)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tiers",
+    tag = "tiers",
+    responses((status = 200, body = [TierSummary]))
+)]
+async fn tiers(State(cache): State<Arc<RwLock<Data>>>) -> Response {
+    let data = cache.read().await;
+    let tiers: Vec<TierSummary> = data
+        .matches
+        .iter()
+        .map(|(&index, m)| to_tier_summary(index, m))
+        .collect();
+    drop(data);
+    Json(tiers).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tiers/{tier}",
+    tag = "tiers",
+    params(("tier" = usize, Path, description = "Tier number, 1 is the highest")),
+    responses((status = 200, body = TierSummary), (status = 404))
+)]
+async fn tier_summary(State(cache): State<Arc<RwLock<Data>>>, Path(tier): Path<usize>) -> Response {
+    let data = cache.read().await;
+    let Some(m) = tier
+        .checked_sub(1)
+        .and_then(|index| data.matches.get(&index))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    Json(to_tier_summary(tier - 1, m)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/teams",
+    tag = "teams",
+    responses((status = 200, body = [TeamSummary]))
+)]
+async fn teams(State(cache): State<Arc<RwLock<Data>>>) -> Response {
+    let data = cache.read().await;
+    let locations = team_locations(&data);
+    let mut teams: Vec<TeamSummary> = TEAM_NAMES
+        .entries()
+        .map(|(team_id, team_name)| {
+            let location = locations.get(team_id).copied();
+            TeamSummary {
+                team_id: (*team_id).to_string(),
+                team_name: (*team_name).to_string(),
+                tier: location.map(|(tier, _)| tier),
+                color: location.map(|(_, color)| color.to_string()),
+            }
+        })
+        .collect();
+    drop(locations);
+    drop(data);
+
+    // Teams not in a running match go last
+    teams.sort_by(|a, b| {
+        (a.tier.is_none(), a.tier, &a.team_name).cmp(&(b.tier.is_none(), b.tier, &b.team_name))
+    });
+    Json(teams).into_response()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SnapshotQuery {
+    /// Maximum number of points, older ones are thinned out evenly
+    points: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tiers/{tier}/snapshots",
+    tag = "tiers",
+    params(("tier" = usize, Path, description = "Tier number, 1 is the highest"), SnapshotQuery),
+    responses((status = 200, body = [Snapshot]), (status = 404))
+)]
+async fn tier_snapshots(
+    State(pool): State<SqlitePool>,
+    Path(tier): Path<usize>,
+    Query(query): Query<SnapshotQuery>,
+) -> Response {
+    let Some(tier) = Tier::from_number(tier) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let Some(m) = get_match(&pool, tier).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Only the running week, older rows belong to a previous match with the same id
+    let since = m
+        .start_time
+        .parse::<DateTime<Utc>>()
+        .map_or(m.start_time, |start| start.to_rfc3339());
+
+    let points = query.points.unwrap_or(DEFAULT_POINTS).clamp(1, MAX_POINTS);
+
+    Json(downsample(get_snapshots(&pool, tier, &since).await, points)).into_response()
+}
+
+fn downsample<T: Clone>(items: Vec<T>, max_points: usize) -> Vec<T> {
+    if max_points == 0 {
+        return Vec::new();
+    }
+    if items.len() <= max_points {
+        return items;
+    }
+
+    let step = items.len().div_ceil(max_points);
+    let last = items
+        .last()
+        .filter(|_| !(items.len() - 1).is_multiple_of(step))
+        .cloned();
+
+    let mut sampled: Vec<T> = items.into_iter().step_by(step).collect();
+    // Always keep the newest value so the chart ends at the current state
+    if let Some(last) = last {
+        if sampled.len() == max_points {
+            sampled.pop();
+        }
+        sampled.push(last);
+    }
+    sampled
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryQuery {
+    tier: Option<usize>,
+    /// Only matches this team id took part in
+    team: Option<String>,
+    limit: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/history/matches",
+    tag = "history",
+    params(HistoryQuery),
+    responses((status = 200, body = [ArchivedMatch]), (status = 400))
+)]
+async fn history_matches(
+    State(pool): State<SqlitePool>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    let tier = match query.tier.map(Tier::from_number) {
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        Some(tier) => tier,
+        None => None,
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    match get_archived_matches(&pool, tier, query.team.as_deref(), limit).await {
+        Ok(matches) => Json(matches).into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/history/matches/{week_key}",
+    tag = "history",
+    params(("week_key" = String, Path, description = "Match id and reset week, e.g. 2025-03-07_2-1")),
+    responses((status = 200, body = ArchivedMatchDetail), (status = 404))
+)]
+async fn history_match(State(pool): State<SqlitePool>, Path(week_key): Path<String>) -> Response {
+    let archived = match get_archived_match(&pool, &week_key).await {
+        Ok(Some(archived)) => archived,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => return internal_error(err),
+    };
+
+    let mut rosters: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    match get_archived_roster(&pool, &week_key).await {
+        Ok(rows) => {
+            for (team_id, guild) in rows {
+                rosters.entry(team_id).or_default().push(guild);
+            }
+        }
+        Err(err) => return internal_error(err),
+    }
+
+    Json(ArchivedMatchDetail { archived, rosters }).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/predictions",
+    tag = "predictions",
+    responses((status = 200, body = Prediction))
+)]
+async fn predictions(State(cache): State<Arc<RwLock<Data>>>) -> Response {
+    Json(cache.read().await.prediction.clone()).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/guilds/{id}/teams",
+    tag = "guilds",
+    params(("id" = String, Path, description = "Guild id")),
+    responses((status = 200, body = [TeamMembership]))
+)]
+async fn guild_team_history(State(pool): State<SqlitePool>, Path(id): Path<String>) -> Response {
+    match get_guild_team_history(&pool, &id).await {
+        Ok(mut history) => {
+            for membership in &mut history {
+                membership.team_name = TEAM_NAMES
+                    .get(&membership.team_id)
+                    .map_or_else(|| "Unknown".to_string(), |name| (*name).to_string());
+            }
+            Json(history).into_response()
+        }
+        Err(err) => internal_error(err),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/guilds/{id}/names",
+    tag = "guilds",
+    params(("id" = String, Path, description = "Guild id")),
+    responses((status = 200, body = [GuildName]))
+)]
+async fn guild_names(State(pool): State<SqlitePool>, Path(id): Path<String>) -> Response {
+    match get_guild_names(&pool, &id).await {
+        Ok(names) => Json(names).into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/guilds/{id}/status",
+    tag = "guilds",
+    params(("id" = String, Path, description = "Guild id")),
+    responses((status = 200, body = GuildFetchStatus), (status = 404))
+)]
+async fn guild_status(State(pool): State<SqlitePool>, Path(id): Path<String>) -> Response {
+    match get_guild_status(&pool, &id).await {
+        Ok(Some(status)) => Json(status).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GuildMovesQuery {
+    /// Only moves into or out of this team id
+    team: Option<String>,
+    /// RFC 3339 timestamp
+    since: Option<String>,
+    /// RFC 3339 timestamp
+    until: Option<String>,
+    limit: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/events/guild-moves",
+    tag = "events",
+    params(GuildMovesQuery),
+    responses((status = 200, body = [GuildMove]), (status = 400))
+)]
+async fn guild_moves(
+    State(pool): State<SqlitePool>,
+    Query(query): Query<GuildMovesQuery>,
+) -> Response {
+    let (since, until) = match (
+        normalize_time(query.since.as_deref()),
+        normalize_time(query.until.as_deref()),
+    ) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(status), _) | (_, Err(status)) => return status.into_response(),
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_EVENT_LIMIT)
+        .clamp(1, MAX_EVENT_LIMIT);
+
+    match get_guild_moves(
+        &pool,
+        query.team.as_deref(),
+        since.as_deref(),
+        until.as_deref(),
+        limit,
+    )
+    .await
+    {
+        Ok(moves) => Json(moves).into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchQuery {
+    /// Guild name or tag, accents and case are ignored
+    q: String,
+    limit: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/guilds/search",
+    tag = "guilds",
+    params(SearchQuery),
+    responses((status = 200, body = [GuildSearchResult]), (status = 400))
+)]
+async fn guild_search(State(state): State<ApiState>, Query(query): Query<SearchQuery>) -> Response {
+    if query.q.trim().is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let data = state.cache.read().await;
+    let results = state.search.read().await.search(&query.q, &data, limit);
+    drop(data);
+    Json(results).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/guilds/{id}",
+    tag = "guilds",
+    params(("id" = String, Path, description = "Guild id")),
+    responses((status = 200, body = GuildProfile), (status = 404))
+)]
+async fn guild_profile(State(state): State<ApiState>, Path(id): Path<String>) -> Response {
+    let pool = &state.pool;
+
+    let guild = match get_guild(pool, &id).await {
+        Ok(Some(guild)) => guild,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => return internal_error(err),
+    };
+
+    let team_id = match get_guild_team(pool, &id).await {
+        Ok(team_id) => team_id.flatten(),
+        Err(err) => return internal_error(err),
+    };
+
+    let (status, former_names, mut team_history, claims) = match (
+        get_guild_status(pool, &id).await,
+        get_guild_names(pool, &id).await,
+        get_guild_team_history(pool, &id).await,
+        get_guild_claims(pool, &id).await,
+    ) {
+        (Ok(status), Ok(names), Ok(history), Ok(claims)) => (status, names, history, claims),
+        (Err(err), ..) | (_, Err(err), ..) | (.., Err(err), _) | (.., Err(err)) => {
+            return internal_error(err);
+        }
+    };
+
+    for membership in &mut team_history {
+        membership.team_name = TEAM_NAMES
+            .get(&membership.team_id)
+            .map_or_else(|| "Unknown".to_string(), |name| (*name).to_string());
+    }
+
+    let data = state.cache.read().await;
+    let location = team_id
+        .as_deref()
+        .and_then(|team_id| team_locations(&data).get(team_id).copied());
+
+    Json(GuildProfile {
+        team_name: team_id
+            .as_deref()
+            .and_then(|team_id| TEAM_NAMES.get(team_id))
+            .map(|name| (*name).to_string()),
+        tier: location.map(|(tier, _)| tier),
+        color: location.map(|(_, color)| color.to_string()),
+        last_refreshed: get_last_guild_update(pool, &id)
+            .await
+            .map(|t| t.to_rfc3339()),
+        important: data.important_guilds.contains(&guild.name),
+        status,
+        former_names: former_names
+            .into_iter()
+            .filter(|n| n.name != guild.name || n.tag != guild.tag)
+            .collect(),
+        team_history,
+        claims,
+        team_id,
+        id: guild.id,
+        name: guild.name,
+        tag: guild.tag,
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/guilds/{id}/emblem.svg",
+    tag = "guilds",
+    params(("id" = String, Path, description = "Guild id")),
+    responses(
+        (status = 200, body = String, content_type = "image/svg+xml"),
+        (status = 404, description = "Unknown guild, no emblem or its parts are not fetched yet")
+    )
+)]
+async fn guild_emblem(State(state): State<ApiState>, Path(id): Path<String>) -> Response {
+    match render_guild_emblem(&state.pool, &id).await {
+        Ok(Some(svg)) => (
+            [
+                (header::CONTENT_TYPE, "image/svg+xml"),
+                (header::CACHE_CONTROL, "public, max-age=86400"),
+            ],
+            svg,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/teams/{team_id}",
+    tag = "teams",
+    params(("team_id" = String, Path, description = "Team id, e.g. 11001")),
+    responses((status = 200, body = TeamRoster), (status = 404))
+)]
+async fn team_roster(State(state): State<ApiState>, Path(team_id): Path<String>) -> Response {
+    let Some(team_name) = TEAM_NAMES.get(&team_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let roster = match get_team_roster(&state.pool, &team_id).await {
+        Ok(roster) => roster,
+        Err(err) => return internal_error(err),
+    };
+
+    // All tiers reset together, any running match tells us when the week started
+    let mut reset = None;
+    for tier in Tier::all() {
+        if let Some(m) = get_match(&state.pool, tier).await {
+            reset = normalize_time(Some(&m.start_time)).ok().flatten();
+            break;
+        }
+    }
+
+    let (changes_since_reset, (joined, left, total)) = match reset {
+        Some(since) => {
+            let moves = get_guild_moves(
+                &state.pool,
+                Some(&team_id),
+                Some(&since),
+                None,
+                MAX_EVENT_LIMIT,
+            )
+            .await;
+            // A relink moves more guilds than one listing holds, the counts cover all of them
+            let counts = get_guild_move_counts(&state.pool, &team_id, &since).await;
+            match (moves, counts) {
+                (Ok(moves), Ok(counts)) => (moves, counts),
+                (Err(err), _) | (_, Err(err)) => return internal_error(err),
+            }
+        }
+        None => (Vec::new(), (0, 0, 0)),
+    };
+
+    let data = state.cache.read().await;
+    let location = team_locations(&data).get(team_id.as_str()).copied();
+    let guilds: Vec<RosterGuild> = roster
+        .into_iter()
+        .map(|(guild, status)| RosterGuild {
+            important: data.important_guilds.contains(&guild.name),
+            guild,
+            status,
+        })
+        .collect();
+    drop(data);
+
+    Json(TeamRoster {
+        team_name: (*team_name).to_string(),
+        tier: location.map(|(tier, _)| tier),
+        color: location.map(|(_, color)| color.to_string()),
+        guild_count: guilds.len(),
+        important_guild_count: guilds.iter().filter(|g| g.important).count(),
+        guilds,
+        joined_since_reset: joined,
+        left_since_reset: left,
+        changes_truncated: changes_since_reset.len() < total as usize,
+        changes_since_reset,
+        team_id,
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(serde_json::Value::String(target)) = map.get("$ref") {
+                    found.push(target);
+                }
+                map.values().for_each(|v| refs(v, found));
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(|v| refs(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn guild_status_documents_fetch_status() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let schema = &doc["paths"]["/api/v1/guilds/{id}/status"]["get"]["responses"]["200"]["content"]
+            ["application/json"]["schema"]["$ref"];
+        assert_eq!(schema, "#/components/schemas/GuildFetchStatus");
+
+        let properties = &doc["components"]["schemas"]["GuildFetchStatus"]["properties"];
+        for field in ["status", "failure_count", "next_retry", "last_error"] {
+            assert!(properties.get(field).is_some(), "{field} missing");
+        }
+    }
+
+    #[test]
+    fn every_schema_reference_resolves() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut found = Vec::new();
+        refs(&doc, &mut found);
+        assert!(!found.is_empty());
+
+        for target in found {
+            let name = target.trim_start_matches("#/components/schemas/");
+            assert!(
+                doc["components"]["schemas"].get(name).is_some(),
+                "{target} is not defined"
+            );
+        }
+    }
+
+    #[test]
+    fn downsample_edge_cases() {
+        let items: Vec<u32> = (0..10).collect();
+        assert!(downsample(items.clone(), 0).is_empty());
+        // A single point is the newest one
+        assert_eq!(downsample(items.clone(), 1), [9]);
+        assert_eq!(downsample(items.clone(), 10), items);
+        assert_eq!(downsample(items, 20).len(), 10);
+        assert!(downsample(Vec::<u32>::new(), 5).is_empty());
+    }
+
+    #[test]
+    fn downsample_keeps_first_and_last() {
+        for len in 2..200 {
+            let items: Vec<usize> = (0..len).collect();
+            for max_points in 2..len {
+                let sampled = downsample(items.clone(), max_points);
+                assert!(sampled.len() <= max_points, "{len} into {max_points}");
+                assert_eq!(sampled.first(), Some(&0), "{len} into {max_points}");
+                assert_eq!(sampled.last(), Some(&(len - 1)), "{len} into {max_points}");
+                assert!(sampled.is_sorted(), "{len} into {max_points}");
+            }
+        }
+    }
+}
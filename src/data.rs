@@ -4,11 +4,7 @@ use std::fmt::Display;
 use serde::{Deserialize, Serialize};
 use sqlx::Row;
 use sqlx::{prelude::FromRow, sqlite::SqliteRow};
-
-#[derive(Serialize, Deserialize, FromRow)]
-pub struct Issue {
-    pub text: String,
-}
+use utoipa::ToSchema;
 
 const API_BASE: &str = "https://api.guildwars2.com/v2";
 
@@ -18,6 +14,10 @@ pub enum APIEndpoint {
     Guild(String),
     AllWvWGuilds,
     GuildIDfromName(String),
+    EmblemBackground(u32),
+    EmblemForeground(u32),
+    Colors(Vec<u32>),
+    Render(String),
 }
 
 impl Display for APIEndpoint {
@@ -29,15 +29,148 @@ impl Display for APIEndpoint {
             Self::GuildIDfromName(guild_name) => {
                 write!(f, "{API_BASE}/guild/search?name={guild_name}")
             }
+            Self::EmblemBackground(id) => write!(f, "{API_BASE}/emblem/backgrounds/{id}"),
+            Self::EmblemForeground(id) => write!(f, "{API_BASE}/emblem/foregrounds/{id}"),
+            Self::Colors(ids) => {
+                let ids: Vec<String> = ids.iter().map(ToString::to_string).collect();
+                write!(f, "{API_BASE}/colors?ids={}", ids.join(","))
+            }
+            Self::Render(url) => write!(f, "{url}"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, FromRow)]
+#[derive(Serialize, Deserialize, FromRow, ToSchema)]
 pub struct Guild {
     pub id: String,
     pub name: String,
     pub tag: String,
+    #[serde(default, skip_serializing)]
+    #[sqlx(skip)]
+    pub emblem: Option<Emblem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Hash)]
+pub struct EmblemPart {
+    pub id: u32,
+    pub colors: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Hash)]
+pub struct Emblem {
+    pub background: EmblemPart,
+    pub foreground: EmblemPart,
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EmblemLayers {
+    pub id: u32,
+    pub layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ColorMaterial {
+    pub rgb: [u8; 3],
+}
+
+#[derive(Deserialize)]
+pub struct Color {
+    pub id: u32,
+    pub cloth: ColorMaterial,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum GuildStatus {
+    Active,
+    Gone,
+    Error,
+}
+
+#[derive(FromRow)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub quota_per_minute: Option<u32>,
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct GuildFetchStatus {
+    pub status: GuildStatus,
+    pub failure_count: u32,
+    pub next_retry: Option<String>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct GuildName {
+    pub name: String,
+    pub tag: String,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct GuildProfile {
+    pub id: String,
+    pub name: String,
+    pub tag: String,
+    pub team_id: Option<String>,
+    pub team_name: Option<String>,
+    pub tier: Option<usize>,
+    pub color: Option<String>,
+    pub last_refreshed: Option<String>,
+    pub important: bool,
+    pub status: Option<GuildFetchStatus>,
+    pub former_names: Vec<GuildName>,
+    pub team_history: Vec<TeamMembership>,
+    pub claims: Vec<ObjectiveClaim>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RosterGuild {
+    #[serde(flatten)]
+    pub guild: Guild,
+    pub important: bool,
+    pub status: Option<GuildStatus>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TeamRoster {
+    pub team_id: String,
+    pub team_name: String,
+    pub tier: Option<usize>,
+    pub color: Option<String>,
+    pub guild_count: usize,
+    pub important_guild_count: usize,
+    pub guilds: Vec<RosterGuild>,
+    /// Guilds that joined or switched to this team since the weekly reset
+    pub joined_since_reset: u32,
+    /// Guilds that left or switched away since the weekly reset
+    pub left_since_reset: u32,
+    /// Newest changes first, capped like the events endpoint
+    pub changes_since_reset: Vec<GuildMove>,
+    /// More changes happened than `changes_since_reset` lists
+    pub changes_truncated: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct GuildSearchResult {
+    pub id: String,
+    pub name: String,
+    pub tag: String,
+    pub former_name: Option<String>,
+    pub former_tag: Option<String>,
+    pub team_id: Option<String>,
+    pub team_name: Option<String>,
+    pub tier: Option<usize>,
+    pub color: Option<String>,
+    pub score: u32,
 }
 
 impl Display for Guild {
@@ -46,6 +179,34 @@ impl Display for Guild {
     }
 }
 
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct TeamMembership {
+    pub team_id: String,
+    #[sqlx(skip)]
+    pub team_name: String,
+    pub valid_from: String,
+    pub valid_to: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum MoveKind {
+    Joined,
+    Left,
+    Switched,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct GuildMove {
+    pub guild_id: String,
+    pub guild_name: Option<String>,
+    pub kind: MoveKind,
+    pub from_team: Option<String>,
+    pub to_team: Option<String>,
+    pub occurred_at: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Worlds {
     pub red: u32,
@@ -60,6 +221,71 @@ pub struct VictoryPoints {
     pub blue: u32,
 }
 
+#[derive(Serialize, Deserialize, Default, Clone, Copy, ToSchema)]
+pub struct Scores {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Skirmish {
+    pub id: u32,
+    pub scores: Scores,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MatchMap {
+    pub objectives: Vec<Objective>,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct Objective {
+    pub id: String,
+    #[serde(rename = "type")]
+    #[sqlx(rename = "type")]
+    pub kind: String,
+    pub owner: String,
+    pub last_flipped: Option<String>,
+    #[serde(default)]
+    pub yaks_delivered: u32,
+    #[sqlx(default)]
+    pub claimed_by: Option<String>,
+    #[sqlx(default)]
+    pub claimed_at: Option<String>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct ObjectiveClaim {
+    pub match_id: String,
+    pub objective_id: String,
+    pub objective_type: Option<String>,
+    pub claimed_at: Option<String>,
+}
+
+// Yaks needed to reach upgrade tier 1, 2 and 3
+const TIER_YAKS: [u32; 3] = [20, 60, 140];
+
+impl Objective {
+    pub fn tier(&self) -> usize {
+        TIER_YAKS
+            .iter()
+            .filter(|&&yaks| self.yaks_delivered >= yaks)
+            .count()
+    }
+
+    pub fn points_per_tick(&self) -> u32 {
+        let per_tier: [u32; 4] = match self.kind.as_str() {
+            "Camp" => [2, 3, 4, 5],
+            "Tower" => [4, 6, 8, 10],
+            "Keep" => [8, 12, 16, 20],
+            "Castle" => [12, 18, 24, 30],
+            _ => return 0,
+        };
+        per_tier[self.tier()]
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Match {
     pub id: String,
@@ -67,6 +293,16 @@ pub struct Match {
     pub end_time: String,
     pub worlds: Worlds,
     pub victory_points: VictoryPoints,
+    #[serde(default)]
+    pub scores: Scores,
+    #[serde(default)]
+    pub kills: Scores,
+    #[serde(default)]
+    pub deaths: Scores,
+    #[serde(default)]
+    pub skirmishes: Vec<Skirmish>,
+    #[serde(default)]
+    pub maps: Vec<MatchMap>,
 }
 
 impl<'r> FromRow<'r, SqliteRow> for Match {
@@ -85,10 +321,93 @@ impl<'r> FromRow<'r, SqliteRow> for Match {
                 green: row.try_get("green_vp")?,
                 blue: row.try_get("blue_vp")?,
             },
+            scores: Scores::default(),
+            kills: Scores::default(),
+            deaths: Scores::default(),
+            skirmishes: Vec::new(),
+            maps: Vec::new(),
+        })
+    }
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct Snapshot {
+    pub taken_at: String,
+    pub victory_points: Scores,
+    pub scores: Scores,
+    pub kills: Scores,
+    pub deaths: Scores,
+}
+
+fn scores_from_row(row: &SqliteRow, suffix: &str) -> Result<Scores, sqlx::Error> {
+    Ok(Scores {
+        red: row.try_get(format!("red_{suffix}").as_str())?,
+        green: row.try_get(format!("green_{suffix}").as_str())?,
+        blue: row.try_get(format!("blue_{suffix}").as_str())?,
+    })
+}
+
+impl<'r> FromRow<'r, SqliteRow> for Snapshot {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            taken_at: row.try_get("taken_at")?,
+            victory_points: scores_from_row(row, "vp")?,
+            scores: scores_from_row(row, "score")?,
+            kills: scores_from_row(row, "kills")?,
+            deaths: scores_from_row(row, "deaths")?,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArchivedTeam {
+    pub team_id: String,
+    pub team_name: String,
+    pub victory_points: u32,
+    pub placement: u8,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArchivedMatch {
+    pub week_key: String,
+    pub match_id: String,
+    pub start_time: String,
+    pub end_time: String,
+    pub red: ArchivedTeam,
+    pub green: ArchivedTeam,
+    pub blue: ArchivedTeam,
+}
+
+fn archived_team_from_row(row: &SqliteRow, color: &str) -> Result<ArchivedTeam, sqlx::Error> {
+    Ok(ArchivedTeam {
+        team_id: row.try_get(format!("{color}_team").as_str())?,
+        team_name: row.try_get(format!("{color}_team_name").as_str())?,
+        victory_points: row.try_get(format!("{color}_vp").as_str())?,
+        placement: row.try_get(format!("{color}_placement").as_str())?,
+    })
+}
+
+impl<'r> FromRow<'r, SqliteRow> for ArchivedMatch {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            week_key: row.try_get("week_key")?,
+            match_id: row.try_get("match_id")?,
+            start_time: row.try_get("start_time")?,
+            end_time: row.try_get("end_time")?,
+            red: archived_team_from_row(row, "red")?,
+            green: archived_team_from_row(row, "green")?,
+            blue: archived_team_from_row(row, "blue")?,
         })
     }
 }
 
+#[derive(Serialize, ToSchema)]
+pub struct ArchivedMatchDetail {
+    #[serde(flatten)]
+    pub archived: ArchivedMatch,
+    pub rosters: BTreeMap<String, Vec<Guild>>,
+}
+
 #[derive(Clone, Copy)]
 pub enum Tier {
     One,
@@ -112,6 +431,10 @@ impl Tier {
     pub fn all() -> Vec<Self> {
         vec![Self::One, Self::Two, Self::Three, Self::Four, Self::Five]
     }
+
+    pub fn from_number(n: usize) -> Option<Self> {
+        n.checked_sub(1).and_then(|i| Self::all().get(i).copied())
+    }
 }
 
 impl Display for Tier {
@@ -130,8 +453,13 @@ impl Display for Tier {
 
 #[derive(Serialize, Default, Clone, Hash)]
 pub struct MatchColor {
+    pub team_id: String,
     pub team_name: String,
     pub victory_points: String,
+    pub points_per_tick: u32,
+    pub skirmish_score: u32,
+    pub projected_score: u32,
+    pub projected_placement: u8,
     pub guilds: BTreeMap<char, Vec<String>>,
 }
 
@@ -140,6 +468,83 @@ pub struct MatchData {
     pub red: MatchColor,
     pub green: MatchColor,
     pub blue: MatchColor,
+    pub skirmishes_remaining: u32,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct MatchTeam {
+    pub color: String,
+    pub team_id: String,
+    pub team_name: String,
+    pub victory_points: u32,
+    pub points_per_tick: u32,
+    pub skirmish_score: u32,
+    pub projected_score: u32,
+    pub projected_placement: u8,
+    pub guild_count: usize,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct TierMatch {
+    pub tier: usize,
+    pub match_id: String,
+    pub skirmishes_remaining: u32,
+    pub teams: Vec<MatchTeam>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct TeamSummary {
+    pub team_id: String,
+    pub team_name: String,
+    pub tier: Option<usize>,
+    pub color: Option<String>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct TierSummary {
+    pub tier: usize,
+    pub match_id: String,
+    pub teams: Vec<TeamSummary>,
+}
+
+#[derive(Serialize, Clone, Copy, Hash, PartialEq, Eq, Debug, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Movement {
+    Up,
+    Stay,
+    Down,
+}
+
+#[derive(Serialize, Clone, Copy, Hash, PartialEq, Eq, Debug, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Confidence {
+    Locked,
+    Likely,
+    Contested,
+}
+
+#[derive(Serialize, Clone, Hash, ToSchema)]
+pub struct TeamPrediction {
+    pub team_id: String,
+    pub team_name: String,
+    pub tier: usize,
+    pub next_tier: usize,
+    pub movement: Movement,
+    pub vp_margin: Option<u32>,
+    pub confidence: Confidence,
+}
+
+#[derive(Serialize, Clone, Hash, ToSchema)]
+pub struct NextMatchup {
+    pub tier: usize,
+    pub teams: Vec<String>,
+}
+
+#[derive(Serialize, Default, Clone, Hash, ToSchema)]
+pub struct Prediction {
+    pub teams: Vec<TeamPrediction>,
+    pub next_matchups: Vec<NextMatchup>,
+    pub our_team_line: String,
 }
 
 #[derive(Serialize, Default, Clone, Hash)]
@@ -147,4 +552,5 @@ pub struct Data {
     pub matches: BTreeMap<usize, MatchData>,
     pub important_guilds: Vec<String>,
     pub our_team: String,
+    pub prediction: Prediction,
 }
@@ -4,16 +4,41 @@
 Note: all functions in this file swallow errors by just passing to to log_error
 */
 
-use std::{env, fs, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::PathBuf,
+    sync::LazyLock,
+};
 
 use chrono::{DateTime, Duration, Utc};
 use sqlx::{Sqlite, SqlitePool, sqlite::SqlitePoolOptions};
+use tokio::sync::watch;
 
 use crate::{
-    data::{Guild, Match, Tier},
+    data::{
+        ApiKey, ArchivedMatch, ArchivedTeam, Color, Emblem, Guild, GuildFetchStatus, GuildMove,
+        GuildName, GuildStatus, Match, Objective, ObjectiveClaim, Scores, Skirmish, Snapshot,
+        TeamMembership, Tier,
+    },
+    rate_limiter::ApiError,
     tasks::log_error,
 };
 
+// Statements binding values per row are split so their variables stay under SQLite's limit of 32766
+const MAX_INSERT_ROWS: usize = 6000;
+
+// Bumped after every write that can change what build_data returns
+static CHANGES: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::channel(0).0);
+
+pub fn subscribe_changes() -> watch::Receiver<u64> {
+    CHANGES.subscribe()
+}
+
+fn signal_change() {
+    CHANGES.send_modify(|version| *version = version.wrapping_add(1));
+}
+
 pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
     let default_path = "mydb.sqlite";
     let db_path: PathBuf = env::current_exe()
@@ -55,6 +80,13 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         .execute(&pool)
         .await?;
 
+    create_tables(&pool).await?;
+
+    Ok(pool)
+}
+
+#[allow(clippy::too_many_lines)]
+async fn create_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     sqlx::query(
         r"
         CREATE TABLE IF NOT EXISTS guilds (
@@ -64,7 +96,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         );
         ",
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     sqlx::query(
@@ -75,18 +107,166 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         );
         ",
     )
-    .execute(&pool)
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS guild_names (
+            guild_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            tag TEXT NOT NULL,
+            first_seen TEXT NOT NULL,
+            last_seen TEXT NOT NULL,
+            PRIMARY KEY (guild_id, name, tag)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS guild_emblems (
+            guild_id TEXT PRIMARY KEY,
+            emblem TEXT NOT NULL           -- JSON as returned by /v2/guild/:id
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS emblem_layers (
+            kind TEXT NOT NULL,            -- background or foreground
+            id INTEGER NOT NULL,
+            layers TEXT NOT NULL,          -- JSON list of image urls
+            PRIMARY KEY (kind, id)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS emblem_colors (
+            id INTEGER PRIMARY KEY,
+            red INTEGER NOT NULL,
+            green INTEGER NOT NULL,
+            blue INTEGER NOT NULL
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS emblem_images (
+            url TEXT PRIMARY KEY,
+            data BLOB NOT NULL
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS guild_status (
+            guild_id TEXT PRIMARY KEY,
+            status TEXT NOT NULL,          -- active, gone or error
+            failure_count INTEGER NOT NULL,
+            next_retry TEXT,
+            last_error TEXT
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    // Guilds stored before the history existed start out with their current name
+    sqlx::query(
+        r"
+        INSERT OR IGNORE INTO guild_names (guild_id, name, tag, first_seen, last_seen)
+        SELECT g.id, g.name, COALESCE(g.tag, ''), COALESCE(u.last_update, ?1), COALESCE(u.last_update, ?1)
+        FROM guilds g
+        LEFT JOIN guild_last_updated u ON u.guild_id = g.id;
+        ",
+    )
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS guild_team_history (
+            guild_id TEXT NOT NULL,
+            team_id TEXT NOT NULL,
+            valid_from TEXT NOT NULL,
+            valid_to TEXT,                 -- NULL while the assignment is current
+            PRIMARY KEY (guild_id, valid_from)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE INDEX IF NOT EXISTS guild_team_history_current
+        ON guild_team_history (guild_id) WHERE valid_to IS NULL;
+        ",
+    )
+    .execute(pool)
     .await?;
 
     sqlx::query(
         r"
-        CREATE TABLE IF NOT EXISTS guild_team (
-            guild_id TEXT PRIMARY KEY,     -- each guild belongs to only one team
-            team_id TEXT
+        CREATE TABLE IF NOT EXISTS guild_move_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            guild_id TEXT NOT NULL,
+            kind TEXT NOT NULL,            -- joined, left or switched
+            from_team TEXT,
+            to_team TEXT,
+            occurred_at TEXT NOT NULL
         );
         ",
     )
-    .execute(&pool)
+    .execute(pool)
+    .await?;
+
+    // guild_team used to be a plain table, carry its assignments over once
+    let guild_team_kind: Option<String> =
+        sqlx::query_scalar("SELECT type FROM sqlite_master WHERE name = 'guild_team'")
+            .fetch_optional(pool)
+            .await?;
+
+    if guild_team_kind.as_deref() == Some("table") {
+        sqlx::query(
+            r"
+            INSERT OR IGNORE INTO guild_team_history (guild_id, team_id, valid_from)
+            SELECT guild_id, team_id, ? FROM guild_team WHERE team_id IS NOT NULL;
+            ",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await?;
+
+        sqlx::query("DROP TABLE guild_team;").execute(pool).await?;
+    }
+
+    sqlx::query(
+        r"
+        CREATE VIEW IF NOT EXISTS guild_team AS
+        SELECT guild_id, team_id
+        FROM guild_team_history
+        WHERE valid_to IS NULL;
+        ",
+    )
+    .execute(pool)
     .await?;
 
     sqlx::query(
@@ -104,30 +284,299 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         );
         ",
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
-    Ok(pool)
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS objectives (
+            match_id TEXT NOT NULL,
+            id TEXT NOT NULL,
+            type TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            last_flipped TEXT,
+            yaks_delivered INTEGER NOT NULL,
+            PRIMARY KEY (match_id, id)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS objective_claims (
+            match_id TEXT NOT NULL,
+            objective_id TEXT NOT NULL,
+            guild_id TEXT NOT NULL,
+            claimed_at TEXT,
+            PRIMARY KEY (match_id, objective_id)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS skirmish_scores (
+            match_id TEXT PRIMARY KEY,     -- only the skirmish in progress is kept
+            skirmish_id INTEGER NOT NULL,
+            red INTEGER NOT NULL,
+            green INTEGER NOT NULL,
+            blue INTEGER NOT NULL
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS match_snapshots (
+            match_id TEXT NOT NULL,
+            taken_at TEXT NOT NULL,
+            red_vp INTEGER NOT NULL,
+            green_vp INTEGER NOT NULL,
+            blue_vp INTEGER NOT NULL,
+            red_score INTEGER NOT NULL,
+            green_score INTEGER NOT NULL,
+            blue_score INTEGER NOT NULL,
+            red_kills INTEGER NOT NULL,
+            green_kills INTEGER NOT NULL,
+            blue_kills INTEGER NOT NULL,
+            red_deaths INTEGER NOT NULL,
+            green_deaths INTEGER NOT NULL,
+            blue_deaths INTEGER NOT NULL,
+            PRIMARY KEY (match_id, taken_at)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS match_archive (
+            week_key TEXT PRIMARY KEY,     -- start date + match id, e.g. 2025-10-17_2-1
+            match_id TEXT NOT NULL,
+            start_time TEXT NOT NULL,
+            end_time TEXT NOT NULL,
+            red_team TEXT NOT NULL,
+            green_team TEXT NOT NULL,
+            blue_team TEXT NOT NULL,
+            red_team_name TEXT NOT NULL,
+            green_team_name TEXT NOT NULL,
+            blue_team_name TEXT NOT NULL,
+            red_vp INTEGER NOT NULL,
+            green_vp INTEGER NOT NULL,
+            blue_vp INTEGER NOT NULL,
+            red_placement INTEGER NOT NULL,
+            green_placement INTEGER NOT NULL,
+            blue_placement INTEGER NOT NULL
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS match_archive_rosters (
+            week_key TEXT NOT NULL,
+            team_id TEXT NOT NULL,
+            guild_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            tag TEXT,
+            PRIMARY KEY (week_key, guild_id)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    // Only the SHA-256 of a key is kept, the key itself is shown once when issued
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS api_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            key_hash TEXT NOT NULL UNIQUE,
+            quota_per_minute INTEGER,
+            created_at TEXT NOT NULL,
+            revoked_at TEXT
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
 }
 
 pub async fn upsert_guild(pool: &SqlitePool, guild: Guild) {
-    if let Err(err) = sqlx::query("INSERT OR REPLACE INTO guilds (id, name, tag) VALUES (?, ?, ?)")
-        .bind(&guild.id)
-        .bind(&guild.name)
-        .bind(&guild.tag)
-        .execute(pool)
-        .await
+    // Unchanged guilds affect no row, refreshing them doesn't trigger a cache rebuild
+    match sqlx::query(
+        r"
+        INSERT INTO guilds (id, name, tag) VALUES (?, ?, ?)
+        ON CONFLICT(id) DO UPDATE SET name = excluded.name, tag = excluded.tag
+        WHERE name != excluded.name OR tag IS NOT excluded.tag
+        ",
+    )
+    .bind(&guild.id)
+    .bind(&guild.name)
+    .bind(&guild.tag)
+    .execute(pool)
+    .await
+    {
+        Ok(result) if result.rows_affected() > 0 => signal_change(),
+        Ok(_) => {}
+        Err(err) => {
+            log_error(err);
+            return;
+        }
+    }
+
+    let now = Utc::now();
+
+    let emblem_result = match &guild.emblem {
+        Some(emblem) => {
+            sqlx::query("INSERT OR REPLACE INTO guild_emblems (guild_id, emblem) VALUES (?, ?)")
+                .bind(&guild.id)
+                .bind(serde_json::to_string(emblem).unwrap_or_default())
+                .execute(pool)
+                .await
+        }
+        None => {
+            sqlx::query("DELETE FROM guild_emblems WHERE guild_id = ?")
+                .bind(&guild.id)
+                .execute(pool)
+                .await
+        }
+    };
+
+    if let Err(err) = emblem_result {
+        log_error(err);
+    }
+
+    if let Err(err) = sqlx::query(
+        r"
+        INSERT INTO guild_names (guild_id, name, tag, first_seen, last_seen)
+        VALUES (?1, ?2, ?3, ?4, ?4)
+        ON CONFLICT(guild_id, name, tag) DO UPDATE SET last_seen = excluded.last_seen
+        ",
+    )
+    .bind(&guild.id)
+    .bind(&guild.name)
+    .bind(&guild.tag)
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await
+    {
+        log_error(err);
+    }
+
+    if let Err(err) = sqlx::query(
+        r"
+        INSERT OR REPLACE INTO guild_status (guild_id, status, failure_count, next_retry, last_error)
+        VALUES (?, ?, 0, NULL, NULL)
+        ",
+    )
+    .bind(&guild.id)
+    .bind(GuildStatus::Active)
+    .execute(pool)
+    .await
     {
         log_error(err);
-        return;
     }
 
-    if let Err(err) = upsert_last_updated(pool, &guild.id, Utc::now()).await {
+    if let Err(err) = upsert_last_updated(pool, &guild.id, now).await {
         log_error(err);
     }
 }
 
-#[allow(dead_code)]
+// 5 minutes after the first failure, doubling up to a day
+fn retry_delay(failure_count: u32) -> Duration {
+    let minutes = 5_i64.saturating_mul(1 << failure_count.saturating_sub(1).min(16));
+    Duration::minutes(minutes.min(24 * 60))
+}
+
+pub async fn record_guild_failure(pool: &SqlitePool, guild_id: &str, error: &ApiError) {
+    let failure_count = match sqlx::query_scalar::<_, u32>(
+        "SELECT failure_count FROM guild_status WHERE guild_id = ?",
+    )
+    .bind(guild_id)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(count) => count.unwrap_or(0) + 1,
+        Err(err) => {
+            log_error(err);
+            return;
+        }
+    };
+
+    let (status, next_retry, last_error) = match error {
+        ApiError::NotFound => (GuildStatus::Gone, None, "not found".to_string()),
+        ApiError::Failed(reason) => (
+            GuildStatus::Error,
+            Some((Utc::now() + retry_delay(failure_count)).to_rfc3339()),
+            reason.clone(),
+        ),
+    };
+
+    if let Err(err) = sqlx::query(
+        r"
+        INSERT OR REPLACE INTO guild_status (guild_id, status, failure_count, next_retry, last_error)
+        VALUES (?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(guild_id)
+    .bind(status)
+    .bind(failure_count)
+    .bind(next_retry)
+    .bind(last_error)
+    .execute(pool)
+    .await
+    {
+        log_error(err);
+    }
+}
+
+pub async fn guild_fetch_allowed(pool: &SqlitePool, guild_id: &str) -> bool {
+    match sqlx::query_scalar::<_, i64>(
+        r"
+        SELECT 1 FROM guild_status
+        WHERE guild_id = ?
+          AND (status = 'gone' OR (status = 'error' AND next_retry > ?))
+        LIMIT 1
+        ",
+    )
+    .bind(guild_id)
+    .bind(Utc::now().to_rfc3339())
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(blocked) => blocked.is_none(),
+        Err(err) => {
+            log_error(err);
+            false
+        }
+    }
+}
+
+pub async fn get_guild_status(
+    pool: &SqlitePool,
+    guild_id: &str,
+) -> Result<Option<GuildFetchStatus>, sqlx::Error> {
+    sqlx::query_as::<_, GuildFetchStatus>(
+        "SELECT status, failure_count, next_retry, last_error FROM guild_status WHERE guild_id = ?",
+    )
+    .bind(guild_id)
+    .fetch_optional(pool)
+    .await
+}
+
 pub async fn get_guild(pool: &SqlitePool, guild_id: &str) -> Result<Option<Guild>, sqlx::Error> {
     let guild = sqlx::query_as::<_, Guild>("SELECT id, name, tag FROM guilds WHERE id = ?")
         .bind(guild_id)
@@ -169,7 +618,6 @@ pub async fn upsert_last_updated(
     Ok(())
 }
 
-#[allow(dead_code)]
 pub async fn get_last_guild_update(pool: &SqlitePool, guild_id: &str) -> Option<DateTime<Utc>> {
     match sqlx::query_scalar::<Sqlite, String>("SELECT last_update FROM guild_last_updated WHERE guild_id = ?")
             .bind(guild_id)
@@ -190,41 +638,86 @@ pub async fn upsert_guild_teams_bulk(pool: &SqlitePool, guild_list: Vec<(String,
         return;
     }
 
-    let placeholders: Vec<String> = (0..guild_list.len())
-        .map(|_| "(?, ?)".to_string())
-        .collect();
+    let now = Utc::now().to_rfc3339();
 
-    let sql = format!(
-        "INSERT OR REPLACE INTO guild_team (guild_id, team_id) VALUES {};",
-        placeholders.join(", ")
-    );
+    let result: Result<u64, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
 
-    let mut query = sqlx::query(&sql);
+        let mut changed = 0;
+        for chunk in guild_list.chunks(MAX_INSERT_ROWS) {
+            let values = format!(
+                "WITH new (guild_id, team_id) AS (VALUES {})",
+                vec!["(?, ?)"; chunk.len()].join(", ")
+            );
+
+            // Close assignments that changed, then open one for every guild without a current row
+            let close_sql = format!(
+                r"
+                {values}
+                UPDATE guild_team_history SET valid_to = ?
+                FROM new
+                WHERE guild_team_history.valid_to IS NULL
+                  AND guild_team_history.guild_id = new.guild_id
+                  AND guild_team_history.team_id != new.team_id;
+                "
+            );
+            let open_sql = format!(
+                r"
+                {values}
+                INSERT INTO guild_team_history (guild_id, team_id, valid_from)
+                SELECT guild_id, team_id, ? FROM new
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM guild_team_history h
+                    WHERE h.guild_id = new.guild_id AND h.valid_to IS NULL
+                );
+                "
+            );
+
+            for sql in [&close_sql, &open_sql] {
+                let mut query = sqlx::query(sql);
+                for (guild_id, team_id) in chunk {
+                    query = query.bind(guild_id);
+                    query = query.bind(team_id);
+                }
+                changed += query.bind(&now).execute(&mut *tx).await?.rows_affected();
+            }
+        }
 
-    for (guild_id, team_id) in guild_list {
-        query = query.bind(guild_id);
-        query = query.bind(team_id);
+        tx.commit().await?;
+        Ok(changed)
     }
+    .await;
 
-    if let Err(err) = query.execute(pool).await {
-        log_error(err);
+    match result {
+        Ok(changed) if changed > 0 => signal_change(),
+        Ok(_) => {}
+        Err(err) => log_error(err),
     }
 }
 
 #[allow(dead_code)]
 pub async fn upsert_guild_team(pool: &SqlitePool, guild_id: &str, team_id: Option<&str>) {
-    if let Err(err) = sqlx::query(
-        r"
-        INSERT OR REPLACE INTO guild_team (guild_id, team_id)
-        VALUES (?, ?)
-        ",
-    )
-    .bind(guild_id)
-    .bind(team_id) // Option<&str> works; NULL if None
-    .execute(pool)
-    .await
-    {
-        log_error(err);
+    match team_id {
+        Some(team_id) => {
+            upsert_guild_teams_bulk(pool, vec![(guild_id.to_string(), team_id.to_string())]).await;
+        }
+        None => {
+            match sqlx::query(
+                r"
+                UPDATE guild_team_history SET valid_to = ?
+                WHERE guild_id = ? AND valid_to IS NULL
+                ",
+            )
+            .bind(Utc::now().to_rfc3339())
+            .bind(guild_id)
+            .execute(pool)
+            .await
+            {
+                Ok(result) if result.rows_affected() > 0 => signal_change(),
+                Ok(_) => {}
+                Err(err) => log_error(err),
+            }
+        }
     }
 }
 
@@ -232,14 +725,20 @@ pub async fn guilds_to_update(pool: &SqlitePool) -> Vec<String> {
     let cutoff = Utc::now() - Duration::hours(24);
     let cutoff_str = cutoff.to_rfc3339();
 
+    // Gone guilds are never refreshed again, failing ones wait for their backoff
     match sqlx::query_scalar::<_, String>(
         r"
-        SELECT guild_id
-        FROM guild_last_updated
-        WHERE last_update < ?
+        SELECT u.guild_id
+        FROM guild_last_updated u
+        LEFT JOIN guild_status s ON s.guild_id = u.guild_id
+        WHERE u.last_update < ?
+          AND (s.status IS NULL
+            OR s.status = 'active'
+            OR (s.status = 'error' AND s.next_retry <= ?))
         ",
     )
     .bind(&cutoff_str)
+    .bind(Utc::now().to_rfc3339())
     .fetch_all(pool)
     .await
     {
@@ -251,24 +750,42 @@ pub async fn guilds_to_update(pool: &SqlitePool) -> Vec<String> {
     }
 }
 
-pub async fn upsert_guild_team_null(pool: &SqlitePool, excluded_guild_ids: Vec<String>) {
-    let placeholders = excluded_guild_ids
-        .iter()
-        .enumerate()
-        .map(|(i, _)| format!("?{}", i + 1))
-        .collect::<Vec<_>>()
-        .join(", ");
+pub async fn upsert_guild_team_null(pool: &SqlitePool, current_guild_ids: Vec<String>) {
+    let current: HashSet<String> = current_guild_ids.into_iter().collect();
+    let now = Utc::now().to_rfc3339();
+
+    // Only guilds that left WvW are closed, compared here instead of binding every current id
+    let result: Result<u64, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
 
-    let query =
-        format!("UPDATE guild_team SET team_id = NULL WHERE guild_id NOT IN ({placeholders})");
+        let open: Vec<String> =
+            sqlx::query_scalar("SELECT guild_id FROM guild_team_history WHERE valid_to IS NULL")
+                .fetch_all(&mut *tx)
+                .await?;
+        let left: Vec<&String> = open.iter().filter(|id| !current.contains(*id)).collect();
 
-    let mut q = sqlx::query(&query);
-    for id in &excluded_guild_ids {
-        q = q.bind(id);
+        let mut closed = 0;
+        for chunk in left.chunks(MAX_INSERT_ROWS) {
+            let sql = format!(
+                "UPDATE guild_team_history SET valid_to = ? WHERE valid_to IS NULL AND guild_id IN ({})",
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut query = sqlx::query(&sql).bind(&now);
+            for guild_id in chunk {
+                query = query.bind(*guild_id);
+            }
+            closed += query.execute(&mut *tx).await?.rows_affected();
+        }
+
+        tx.commit().await?;
+        Ok(closed)
     }
+    .await;
 
-    if let Err(err) = q.execute(pool).await {
-        log_error(err);
+    match result {
+        Ok(closed) if closed > 0 => signal_change(),
+        Ok(_) => {}
+        Err(err) => log_error(err),
     }
 }
 
@@ -289,7 +806,11 @@ pub async fn upsert_match(pool: &SqlitePool, m: &Match) {
             blue_world = excluded.blue_world,
             red_vp = excluded.red_vp,
             green_vp = excluded.green_vp,
-            blue_vp = excluded.blue_vp;
+            blue_vp = excluded.blue_vp
+        WHERE (start_time, end_time, red_world, green_world, blue_world, red_vp, green_vp, blue_vp)
+            IS NOT (excluded.start_time, excluded.end_time, excluded.red_world,
+                    excluded.green_world, excluded.blue_world,
+                    excluded.red_vp, excluded.green_vp, excluded.blue_vp);
         ",
     )
     .bind(&m.id)
@@ -304,11 +825,280 @@ pub async fn upsert_match(pool: &SqlitePool, m: &Match) {
     .execute(pool)
     .await;
 
+    // Polls mostly return the same match, only real changes trigger a rebuild
+    match query_result {
+        Ok(result) if result.rows_affected() > 0 => signal_change(),
+        Ok(_) => {}
+        Err(err) => log_error(err),
+    }
+}
+
+pub async fn insert_snapshot(pool: &SqlitePool, m: &Match, taken_at: DateTime<Utc>) {
+    let query_result = sqlx::query(
+        r"
+        INSERT OR IGNORE INTO match_snapshots (
+            match_id, taken_at,
+            red_vp, green_vp, blue_vp,
+            red_score, green_score, blue_score,
+            red_kills, green_kills, blue_kills,
+            red_deaths, green_deaths, blue_deaths
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&m.id)
+    .bind(taken_at.to_rfc3339())
+    .bind(m.victory_points.red)
+    .bind(m.victory_points.green)
+    .bind(m.victory_points.blue)
+    .bind(m.scores.red)
+    .bind(m.scores.green)
+    .bind(m.scores.blue)
+    .bind(m.kills.red)
+    .bind(m.kills.green)
+    .bind(m.kills.blue)
+    .bind(m.deaths.red)
+    .bind(m.deaths.green)
+    .bind(m.deaths.blue)
+    .execute(pool)
+    .await;
+
     if let Err(err) = query_result {
         log_error(err);
     }
 }
 
+// Snapshots are only served for the running week, earlier ones of the same match id go
+pub async fn prune_snapshots(pool: &SqlitePool, match_id: &str, before: DateTime<Utc>) {
+    if let Err(err) = sqlx::query("DELETE FROM match_snapshots WHERE match_id = ? AND taken_at < ?")
+        .bind(match_id)
+        .bind(before.to_rfc3339())
+        .execute(pool)
+        .await
+    {
+        log_error(err);
+    }
+}
+
+pub async fn get_snapshots(pool: &SqlitePool, tier: Tier, since: &str) -> Vec<Snapshot> {
+    match sqlx::query_as::<_, Snapshot>(
+        r"
+        SELECT *
+        FROM match_snapshots
+        WHERE match_id = ? AND taken_at >= ?
+        ORDER BY taken_at
+        ",
+    )
+    .bind(tier.as_id())
+    .bind(since)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(snapshots) => snapshots,
+        Err(err) => {
+            log_error(err);
+            Vec::new()
+        }
+    }
+}
+
+pub async fn upsert_objectives(pool: &SqlitePool, match_id: &str, objectives: &[&Objective]) {
+    if objectives.is_empty() {
+        return;
+    }
+
+    let placeholders: Vec<String> = (0..objectives.len())
+        .map(|_| "(?, ?, ?, ?, ?, ?)".to_string())
+        .collect();
+
+    let sql = format!(
+        r"
+        INSERT INTO objectives (match_id, id, type, owner, last_flipped, yaks_delivered) VALUES {}
+        ON CONFLICT(match_id, id) DO UPDATE SET
+            type = excluded.type,
+            owner = excluded.owner,
+            last_flipped = excluded.last_flipped,
+            yaks_delivered = excluded.yaks_delivered
+        WHERE (type, owner, last_flipped, yaks_delivered)
+            IS NOT (excluded.type, excluded.owner, excluded.last_flipped, excluded.yaks_delivered);
+        ",
+        placeholders.join(", ")
+    );
+
+    let mut query = sqlx::query(&sql);
+
+    for o in objectives {
+        query = query
+            .bind(match_id)
+            .bind(&o.id)
+            .bind(&o.kind)
+            .bind(&o.owner)
+            .bind(&o.last_flipped)
+            .bind(o.yaks_delivered);
+    }
+
+    match query.execute(pool).await {
+        Ok(result) if result.rows_affected() > 0 => signal_change(),
+        Ok(_) => {}
+        Err(err) => log_error(err),
+    }
+}
+
+pub async fn upsert_objective_claims(pool: &SqlitePool, match_id: &str, objectives: &[&Objective]) {
+    let mut claimed: Vec<(&str, &str, Option<&str>)> = objectives
+        .iter()
+        .filter_map(|o| {
+            o.claimed_by
+                .as_deref()
+                .map(|guild_id| (o.id.as_str(), guild_id, o.claimed_at.as_deref()))
+        })
+        .collect();
+    claimed.sort_unstable();
+
+    let result: Result<bool, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+
+        // Claims rarely change between polls, leave the table and the cache alone then
+        let stored: Vec<(String, String, Option<String>)> = sqlx::query_as(
+            "SELECT objective_id, guild_id, claimed_at FROM objective_claims WHERE match_id = ? ORDER BY objective_id",
+        )
+        .bind(match_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let unchanged = stored.len() == claimed.len()
+            && stored.iter().zip(&claimed).all(|(stored, claimed)| {
+                (stored.0.as_str(), stored.1.as_str(), stored.2.as_deref()) == *claimed
+            });
+        if unchanged {
+            return Ok(false);
+        }
+
+        sqlx::query("DELETE FROM objective_claims WHERE match_id = ?")
+            .bind(match_id)
+            .execute(&mut *tx)
+            .await?;
+
+        if !claimed.is_empty() {
+            let placeholders: Vec<String> = (0..claimed.len())
+                .map(|_| "(?, ?, ?, ?)".to_string())
+                .collect();
+
+            let sql = format!(
+                "INSERT INTO objective_claims (match_id, objective_id, guild_id, claimed_at) VALUES {};",
+                placeholders.join(", ")
+            );
+
+            let mut query = sqlx::query(&sql);
+            for (objective_id, guild_id, claimed_at) in &claimed {
+                query = query
+                    .bind(match_id)
+                    .bind(*objective_id)
+                    .bind(*guild_id)
+                    .bind(*claimed_at);
+            }
+            query.execute(&mut *tx).await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+    .await;
+
+    match result {
+        Ok(true) => signal_change(),
+        Ok(false) => {}
+        Err(err) => log_error(err),
+    }
+}
+
+pub async fn get_guild_claims(
+    pool: &SqlitePool,
+    guild_id: &str,
+) -> Result<Vec<ObjectiveClaim>, sqlx::Error> {
+    sqlx::query_as::<_, ObjectiveClaim>(
+        r"
+        SELECT c.match_id, c.objective_id, o.type AS objective_type, c.claimed_at
+        FROM objective_claims c
+        LEFT JOIN objectives o ON o.match_id = c.match_id AND o.id = c.objective_id
+        WHERE c.guild_id = ?
+        ORDER BY c.claimed_at DESC
+        ",
+    )
+    .bind(guild_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn upsert_current_skirmish(pool: &SqlitePool, match_id: &str, skirmish: &Skirmish) {
+    match sqlx::query(
+        r"
+        INSERT INTO skirmish_scores (match_id, skirmish_id, red, green, blue)
+        VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT(match_id) DO UPDATE SET
+            skirmish_id = excluded.skirmish_id,
+            red = excluded.red,
+            green = excluded.green,
+            blue = excluded.blue
+        WHERE (skirmish_id, red, green, blue)
+            IS NOT (excluded.skirmish_id, excluded.red, excluded.green, excluded.blue)
+        ",
+    )
+    .bind(match_id)
+    .bind(skirmish.id)
+    .bind(skirmish.scores.red)
+    .bind(skirmish.scores.green)
+    .bind(skirmish.scores.blue)
+    .execute(pool)
+    .await
+    {
+        Ok(result) if result.rows_affected() > 0 => signal_change(),
+        Ok(_) => {}
+        Err(err) => log_error(err),
+    }
+}
+
+pub async fn get_objectives(pool: &SqlitePool, tier: Tier) -> Vec<Objective> {
+    match sqlx::query_as::<_, Objective>(
+        r"
+        SELECT o.id, o.type, o.owner, o.last_flipped, o.yaks_delivered,
+               c.guild_id AS claimed_by, c.claimed_at
+        FROM objectives o
+        LEFT JOIN objective_claims c ON c.match_id = o.match_id AND c.objective_id = o.id
+        WHERE o.match_id = ?
+        ORDER BY o.id
+        ",
+    )
+    .bind(tier.as_id())
+    .fetch_all(pool)
+    .await
+    {
+        Ok(objectives) => objectives,
+        Err(err) => {
+            log_error(err);
+            Vec::new()
+        }
+    }
+}
+
+pub async fn get_current_skirmish(pool: &SqlitePool, tier: Tier) -> Option<Skirmish> {
+    match sqlx::query_as::<_, (u32, u32, u32, u32)>(
+        "SELECT skirmish_id, red, green, blue FROM skirmish_scores WHERE match_id = ?",
+    )
+    .bind(tier.as_id())
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(row) => row.map(|(id, red, green, blue)| Skirmish {
+            id,
+            scores: Scores { red, green, blue },
+        }),
+        Err(err) => {
+            log_error(err);
+            None
+        }
+    }
+}
+
 pub async fn get_match(pool: &SqlitePool, tier: Tier) -> Option<Match> {
     match sqlx::query_as::<_, Match>(
         r"
@@ -329,7 +1119,6 @@ pub async fn get_match(pool: &SqlitePool, tier: Tier) -> Option<Match> {
     }
 }
 
-#[allow(dead_code)]
 pub async fn get_guild_team(
     pool: &SqlitePool,
     guild_id: &str,
@@ -344,7 +1133,6 @@ pub async fn get_guild_team(
     Ok(Some(team_id))
 }
 
-#[allow(dead_code)]
 pub async fn get_all_guild_teams(
     pool: &SqlitePool,
 ) -> Result<Vec<(String, Option<String>)>, sqlx::Error> {
@@ -406,6 +1194,30 @@ pub async fn get_guilds_for_team(
     Ok(guilds)
 }
 
+// The roster as it was at `at`, a later relink doesn't rewrite it
+pub async fn get_guilds_for_team_at(
+    pool: &SqlitePool,
+    team_id: &str,
+    at: DateTime<Utc>,
+) -> Result<Vec<Guild>, sqlx::Error> {
+    let guilds: Vec<Guild> = sqlx::query_as::<_, Guild>(
+        r"
+        SELECT g.id, g.name, g.tag
+        FROM guilds g
+        JOIN guild_team_history h ON h.guild_id = g.id
+        WHERE h.team_id = ?1
+          AND h.valid_from <= ?2
+          AND (h.valid_to IS NULL OR h.valid_to > ?2)
+        ",
+    )
+    .bind(team_id)
+    .bind(at.to_rfc3339())
+    .fetch_all(pool)
+    .await?;
+
+    Ok(guilds)
+}
+
 pub async fn get_team_id_for_guild(
     pool: &SqlitePool,
     guild_name: &str,
@@ -424,3 +1236,824 @@ pub async fn get_team_id_for_guild(
 
     Ok(team_id)
 }
+
+pub async fn match_archived(pool: &SqlitePool, week_key: &str) -> bool {
+    match sqlx::query_scalar::<_, i64>("SELECT 1 FROM match_archive WHERE week_key = ? LIMIT 1")
+        .bind(week_key)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(a) => a.is_some(),
+        Err(err) => {
+            log_error(err);
+            false
+        }
+    }
+}
+
+pub async fn archive_match(pool: &SqlitePool, archived: &ArchivedMatch) {
+    let teams: [&ArchivedTeam; 3] = [&archived.red, &archived.green, &archived.blue];
+
+    let query_result = sqlx::query(
+        r"
+        INSERT OR IGNORE INTO match_archive (
+            week_key, match_id, start_time, end_time,
+            red_team, green_team, blue_team,
+            red_team_name, green_team_name, blue_team_name,
+            red_vp, green_vp, blue_vp,
+            red_placement, green_placement, blue_placement
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&archived.week_key)
+    .bind(&archived.match_id)
+    .bind(&archived.start_time)
+    .bind(&archived.end_time)
+    .bind(&teams[0].team_id)
+    .bind(&teams[1].team_id)
+    .bind(&teams[2].team_id)
+    .bind(&teams[0].team_name)
+    .bind(&teams[1].team_name)
+    .bind(&teams[2].team_name)
+    .bind(teams[0].victory_points)
+    .bind(teams[1].victory_points)
+    .bind(teams[2].victory_points)
+    .bind(teams[0].placement)
+    .bind(teams[1].placement)
+    .bind(teams[2].placement)
+    .execute(pool)
+    .await;
+
+    if let Err(err) = query_result {
+        log_error(err);
+    }
+}
+
+pub async fn archive_roster(pool: &SqlitePool, week_key: &str, team_id: &str, guilds: &[Guild]) {
+    if guilds.is_empty() {
+        return;
+    }
+
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+
+        for chunk in guilds.chunks(MAX_INSERT_ROWS) {
+            let placeholders = vec!["(?, ?, ?, ?, ?)"; chunk.len()].join(", ");
+            let sql = format!(
+                "INSERT OR IGNORE INTO match_archive_rosters (week_key, team_id, guild_id, name, tag) VALUES {placeholders};"
+            );
+
+            let mut query = sqlx::query(&sql);
+            for g in chunk {
+                query = query
+                    .bind(week_key)
+                    .bind(team_id)
+                    .bind(&g.id)
+                    .bind(&g.name)
+                    .bind(&g.tag);
+            }
+            query.execute(&mut *tx).await?;
+        }
+
+        tx.commit().await
+    }
+    .await;
+
+    if let Err(err) = result {
+        log_error(err);
+    }
+}
+
+pub async fn get_archived_matches(
+    pool: &SqlitePool,
+    tier: Option<Tier>,
+    team_id: Option<&str>,
+    limit: u32,
+) -> Result<Vec<ArchivedMatch>, sqlx::Error> {
+    let match_id = tier.map(Tier::as_id);
+
+    sqlx::query_as::<_, ArchivedMatch>(
+        r"
+        SELECT *
+        FROM match_archive
+        WHERE (?1 IS NULL OR match_id = ?1)
+          AND (?2 IS NULL OR ?2 IN (red_team, green_team, blue_team))
+        ORDER BY start_time DESC, match_id
+        LIMIT ?3
+        ",
+    )
+    .bind(match_id)
+    .bind(team_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_archived_match(
+    pool: &SqlitePool,
+    week_key: &str,
+) -> Result<Option<ArchivedMatch>, sqlx::Error> {
+    sqlx::query_as::<_, ArchivedMatch>("SELECT * FROM match_archive WHERE week_key = ?")
+        .bind(week_key)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn get_archived_roster(
+    pool: &SqlitePool,
+    week_key: &str,
+) -> Result<Vec<(String, Guild)>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (String, String, String, String)>(
+        r"
+        SELECT team_id, guild_id, name, COALESCE(tag, '')
+        FROM match_archive_rosters
+        WHERE week_key = ?
+        ORDER BY name
+        ",
+    )
+    .bind(week_key)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(team_id, id, name, tag)| {
+            (
+                team_id,
+                Guild {
+                    id,
+                    name,
+                    tag,
+                    emblem: None,
+                },
+            )
+        })
+        .collect())
+}
+
+pub async fn get_guild_team_history(
+    pool: &SqlitePool,
+    guild_id: &str,
+) -> Result<Vec<TeamMembership>, sqlx::Error> {
+    sqlx::query_as::<_, TeamMembership>(
+        r"
+        SELECT team_id, valid_from, valid_to
+        FROM guild_team_history
+        WHERE guild_id = ?
+        ORDER BY valid_from DESC
+        ",
+    )
+    .bind(guild_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_guild_moves(pool: &SqlitePool, moves: &[GuildMove]) {
+    if moves.is_empty() {
+        return;
+    }
+
+    // A reset or relink moves thousands of guilds, they are logged all or nothing
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+
+        for chunk in moves.chunks(MAX_INSERT_ROWS) {
+            let placeholders = vec!["(?, ?, ?, ?, ?)"; chunk.len()].join(", ");
+            let sql = format!(
+                "INSERT INTO guild_move_events (guild_id, kind, from_team, to_team, occurred_at) VALUES {placeholders};"
+            );
+
+            let mut query = sqlx::query(&sql);
+            for m in chunk {
+                query = query
+                    .bind(&m.guild_id)
+                    .bind(m.kind)
+                    .bind(&m.from_team)
+                    .bind(&m.to_team)
+                    .bind(&m.occurred_at);
+            }
+            query.execute(&mut *tx).await?;
+        }
+
+        tx.commit().await
+    }
+    .await;
+
+    if let Err(err) = result {
+        log_error(err);
+    }
+}
+
+pub async fn get_guild_moves(
+    pool: &SqlitePool,
+    team_id: Option<&str>,
+    since: Option<&str>,
+    until: Option<&str>,
+    limit: u32,
+) -> Result<Vec<GuildMove>, sqlx::Error> {
+    sqlx::query_as::<_, GuildMove>(
+        r"
+        SELECT e.guild_id, g.name AS guild_name, e.kind, e.from_team, e.to_team, e.occurred_at
+        FROM guild_move_events e
+        LEFT JOIN guilds g ON g.id = e.guild_id
+        WHERE (?1 IS NULL OR ?1 IN (e.from_team, e.to_team))
+          AND (?2 IS NULL OR e.occurred_at >= ?2)
+          AND (?3 IS NULL OR e.occurred_at < ?3)
+        ORDER BY e.id DESC
+        LIMIT ?4
+        ",
+    )
+    .bind(team_id)
+    .bind(since)
+    .bind(until)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+// (joined, left, total) for one team, counted in full whatever a listing is limited to
+pub async fn get_guild_move_counts(
+    pool: &SqlitePool,
+    team_id: &str,
+    since: &str,
+) -> Result<(u32, u32, u32), sqlx::Error> {
+    sqlx::query_as::<_, (u32, u32, u32)>(
+        r"
+        SELECT
+            COALESCE(SUM(to_team = ?1), 0),
+            COALESCE(SUM(from_team = ?1), 0),
+            COUNT(*)
+        FROM guild_move_events
+        WHERE ?1 IN (from_team, to_team) AND occurred_at >= ?2
+        ",
+    )
+    .bind(team_id)
+    .bind(since)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_guild_names(
+    pool: &SqlitePool,
+    guild_id: &str,
+) -> Result<Vec<GuildName>, sqlx::Error> {
+    sqlx::query_as::<_, GuildName>(
+        r"
+        SELECT name, tag, first_seen, last_seen
+        FROM guild_names
+        WHERE guild_id = ?
+        ORDER BY last_seen DESC
+        ",
+    )
+    .bind(guild_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_guilds_with_teams(
+    pool: &SqlitePool,
+) -> Result<Vec<(Guild, Option<String>)>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (String, String, String, Option<String>)>(
+        r"
+        SELECT g.id, g.name, COALESCE(g.tag, ''), gt.team_id
+        FROM guilds g
+        LEFT JOIN guild_team gt ON gt.guild_id = g.id
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, name, tag, team_id)| {
+            (
+                Guild {
+                    id,
+                    name,
+                    tag,
+                    emblem: None,
+                },
+                team_id,
+            )
+        })
+        .collect())
+}
+
+// History rows where either the name or the tag differs from the current one
+pub async fn get_former_guild_names(
+    pool: &SqlitePool,
+) -> Result<Vec<(String, String, String)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, String, String)>(
+        r"
+        SELECT n.guild_id, n.name, n.tag
+        FROM guild_names n
+        JOIN guilds g ON g.id = n.guild_id
+        WHERE n.name != g.name OR n.tag != g.tag
+        ",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_guild_emblem(
+    pool: &SqlitePool,
+    guild_id: &str,
+) -> Result<Option<Emblem>, sqlx::Error> {
+    let emblem: Option<String> =
+        sqlx::query_scalar("SELECT emblem FROM guild_emblems WHERE guild_id = ?")
+            .bind(guild_id)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(emblem.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+pub async fn get_emblem_layers(
+    pool: &SqlitePool,
+    kind: &str,
+    id: u32,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let layers: Option<String> =
+        sqlx::query_scalar("SELECT layers FROM emblem_layers WHERE kind = ? AND id = ?")
+            .bind(kind)
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(layers.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+pub async fn upsert_emblem_layers(
+    pool: &SqlitePool,
+    kind: &str,
+    id: u32,
+    layers: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT OR REPLACE INTO emblem_layers (kind, id, layers) VALUES (?, ?, ?)")
+        .bind(kind)
+        .bind(id)
+        .bind(serde_json::to_string(layers).unwrap_or_default())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_emblem_colors(
+    pool: &SqlitePool,
+    ids: &[u32],
+) -> Result<HashMap<u32, [u8; 3]>, sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let sql =
+        format!("SELECT id, red, green, blue FROM emblem_colors WHERE id IN ({placeholders})");
+
+    let mut query = sqlx::query_as::<_, (u32, u8, u8, u8)>(&sql);
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    Ok(query
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(id, red, green, blue)| (id, [red, green, blue]))
+        .collect())
+}
+
+pub async fn upsert_emblem_colors(pool: &SqlitePool, colors: &[Color]) -> Result<(), sqlx::Error> {
+    if colors.is_empty() {
+        return Ok(());
+    }
+
+    let placeholders: Vec<String> = (0..colors.len())
+        .map(|_| "(?, ?, ?, ?)".to_string())
+        .collect();
+
+    let sql = format!(
+        "INSERT OR REPLACE INTO emblem_colors (id, red, green, blue) VALUES {};",
+        placeholders.join(", ")
+    );
+
+    let mut query = sqlx::query(&sql);
+    for c in colors {
+        let [red, green, blue] = c.cloth.rgb;
+        query = query.bind(c.id).bind(red).bind(green).bind(blue);
+    }
+
+    query.execute(pool).await?;
+    Ok(())
+}
+
+pub async fn get_emblem_image(
+    pool: &SqlitePool,
+    url: &str,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    sqlx::query_scalar("SELECT data FROM emblem_images WHERE url = ?")
+        .bind(url)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn upsert_emblem_image(
+    pool: &SqlitePool,
+    url: &str,
+    data: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT OR REPLACE INTO emblem_images (url, data) VALUES (?, ?)")
+        .bind(url)
+        .bind(data)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_team_roster(
+    pool: &SqlitePool,
+    team_id: &str,
+) -> Result<Vec<(Guild, Option<GuildStatus>)>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (String, String, String, Option<GuildStatus>)>(
+        r"
+        SELECT g.id, g.name, COALESCE(g.tag, ''), s.status
+        FROM guilds g
+        JOIN guild_team gt ON gt.guild_id = g.id
+        LEFT JOIN guild_status s ON s.guild_id = g.id
+        WHERE gt.team_id = ?
+        ORDER BY g.name
+        ",
+    )
+    .bind(team_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, name, tag, status)| {
+            (
+                Guild {
+                    id,
+                    name,
+                    tag,
+                    emblem: None,
+                },
+                status,
+            )
+        })
+        .collect())
+}
+
+pub async fn insert_api_key(
+    pool: &SqlitePool,
+    name: &str,
+    key_hash: &str,
+    quota_per_minute: Option<u32>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO api_keys (name, key_hash, quota_per_minute, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(name)
+    .bind(key_hash)
+    .bind(quota_per_minute)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn revoke_api_key(pool: &SqlitePool, name: &str) -> Result<bool, sqlx::Error> {
+    let result =
+        sqlx::query("UPDATE api_keys SET revoked_at = ? WHERE name = ? AND revoked_at IS NULL")
+            .bind(Utc::now().to_rfc3339())
+            .bind(name)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_api_keys(pool: &SqlitePool) -> Result<Vec<ApiKey>, sqlx::Error> {
+    sqlx::query_as::<_, ApiKey>(
+        "SELECT id, name, quota_per_minute, created_at, revoked_at FROM api_keys ORDER BY id",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_active_api_key(
+    pool: &SqlitePool,
+    key_hash: &str,
+) -> Result<Option<ApiKey>, sqlx::Error> {
+    sqlx::query_as::<_, ApiKey>(
+        r"
+        SELECT id, name, quota_per_minute, created_at, revoked_at
+        FROM api_keys
+        WHERE key_hash = ? AND revoked_at IS NULL
+        ",
+    )
+    .bind(key_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+// Empty schema in memory, for tests of the modules built on top of the database
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    // A single connection, every new one would open its own empty in-memory database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    create_tables(&pool).await.unwrap();
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{MoveKind, VictoryPoints, Worlds};
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn roster_at_ignores_later_relink() {
+        let pool = test_pool().await;
+        sqlx::query("INSERT INTO guilds (id, name, tag) VALUES ('g1', 'Guild One', 'ONE')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        for (team, from, to) in [
+            (
+                "11001",
+                at("2026-09-01T00:00:00Z"),
+                Some(at("2026-10-23T18:05:00Z")),
+            ),
+            ("11002", at("2026-10-23T18:05:00Z"), None),
+        ] {
+            sqlx::query(
+                "INSERT INTO guild_team_history (guild_id, team_id, valid_from, valid_to) VALUES ('g1', ?, ?, ?)",
+            )
+            .bind(team)
+            .bind(from.to_rfc3339())
+            .bind(to.map(|t| t.to_rfc3339()))
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let end_time = at("2026-10-23T18:00:00Z");
+        let old = get_guilds_for_team_at(&pool, "11001", end_time)
+            .await
+            .unwrap();
+        let new = get_guilds_for_team_at(&pool, "11002", end_time)
+            .await
+            .unwrap();
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].id, "g1");
+        assert!(new.is_empty());
+
+        let live = get_guilds_for_team(&pool, "11002").await.unwrap();
+        assert_eq!(live.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn guild_moves_beyond_variable_limit_are_all_logged() {
+        let pool = test_pool().await;
+        // 5 binds per row, a single statement would need 50000 variables
+        let moves: Vec<GuildMove> = (0..10_000)
+            .map(|i| GuildMove {
+                guild_id: format!("guild-{i}"),
+                guild_name: None,
+                kind: MoveKind::Joined,
+                from_team: None,
+                to_team: Some("11001".to_string()),
+                occurred_at: "2026-10-16T18:00:00+00:00".to_string(),
+            })
+            .collect();
+
+        insert_guild_moves(&pool, &moves).await;
+
+        let logged: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM guild_move_events")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(logged, 10_000);
+    }
+
+    fn objective(id: &str, owner: &str, claimed_by: Option<&str>) -> Objective {
+        Objective {
+            id: id.to_string(),
+            kind: "Camp".to_string(),
+            owner: owner.to_string(),
+            last_flipped: Some("2026-10-16T18:00:00Z".to_string()),
+            yaks_delivered: 0,
+            claimed_by: claimed_by.map(str::to_string),
+            claimed_at: claimed_by.map(|_| "2026-10-16T18:01:00Z".to_string()),
+        }
+    }
+
+    fn test_match(id: &str) -> Match {
+        Match {
+            id: id.to_string(),
+            start_time: "2026-10-16T18:00:00Z".to_string(),
+            end_time: "2026-10-23T18:00:00Z".to_string(),
+            worlds: Worlds {
+                red: 1001,
+                green: 1002,
+                blue: 1003,
+            },
+            victory_points: VictoryPoints {
+                red: 5,
+                green: 4,
+                blue: 3,
+            },
+            scores: Scores::default(),
+            kills: Scores::default(),
+            deaths: Scores::default(),
+            skirmishes: Vec::new(),
+            maps: Vec::new(),
+        }
+    }
+
+    // CHANGES is process wide, tests that write through signaling upserts take turns
+    static SIGNALING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[tokio::test]
+    async fn identical_upserts_do_not_signal_a_change() {
+        let _signaling = SIGNALING.lock().await;
+        let pool = test_pool().await;
+        let mut changes = subscribe_changes();
+        let mut m = test_match("1-1");
+        let skirmish = Skirmish {
+            id: 1,
+            scores: Scores::default(),
+        };
+        let camp = objective("38-6", "Red", Some("g1"));
+        let tower = objective("38-8", "Blue", None);
+
+        for expected in [true, false] {
+            changes.borrow_and_update();
+            upsert_match(&pool, &m).await;
+            assert_eq!(changes.has_changed().unwrap(), expected, "match");
+
+            changes.borrow_and_update();
+            upsert_objectives(&pool, &m.id, &[&camp, &tower]).await;
+            assert_eq!(changes.has_changed().unwrap(), expected, "objectives");
+
+            changes.borrow_and_update();
+            upsert_objective_claims(&pool, &m.id, &[&tower, &camp]).await;
+            assert_eq!(changes.has_changed().unwrap(), expected, "claims");
+
+            changes.borrow_and_update();
+            upsert_current_skirmish(&pool, &m.id, &skirmish).await;
+            assert_eq!(changes.has_changed().unwrap(), expected, "skirmish");
+        }
+
+        changes.borrow_and_update();
+        m.victory_points.red += 1;
+        upsert_match(&pool, &m).await;
+        assert!(changes.has_changed().unwrap());
+
+        changes.borrow_and_update();
+        let reclaimed = objective("38-6", "Red", Some("g2"));
+        upsert_objective_claims(&pool, &m.id, &[&reclaimed, &tower]).await;
+        assert!(changes.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn team_assignments_beyond_variable_limit_are_all_recorded() {
+        let _signaling = SIGNALING.lock().await;
+        let pool = test_pool().await;
+        // 2 binds per guild, a single statement would need 40001 variables
+        let guilds: Vec<(String, String)> = (0..20_000)
+            .map(|i| (format!("guild-{i}"), "11001".to_string()))
+            .collect();
+        let open = async || -> Vec<(String, String)> {
+            sqlx::query_as(
+                "SELECT guild_id, team_id FROM guild_team_history WHERE valid_to IS NULL",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+        };
+
+        upsert_guild_teams_bulk(&pool, guilds.clone()).await;
+        assert_eq!(open().await.len(), 20_000);
+
+        // Half of them relink to another team
+        let relinked: Vec<(String, String)> = guilds
+            .iter()
+            .enumerate()
+            .map(|(i, (guild_id, team_id))| {
+                let team_id = if i % 2 == 0 { "11002" } else { team_id };
+                (guild_id.clone(), team_id.to_string())
+            })
+            .collect();
+        upsert_guild_teams_bulk(&pool, relinked).await;
+        let current = open().await;
+        assert_eq!(current.len(), 20_000);
+        assert_eq!(
+            current.iter().filter(|(_, team)| team == "11002").count(),
+            10_000
+        );
+
+        // Everyone but the first 100 leaves WvW
+        upsert_guild_team_null(
+            &pool,
+            guilds[..100].iter().map(|(id, _)| id.clone()).collect(),
+        )
+        .await;
+        assert_eq!(open().await.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn snapshots_before_the_running_week_are_pruned() {
+        let pool = test_pool().await;
+        let m = test_match("1-1");
+        let other_tier = test_match("1-2");
+        for taken_at in [
+            "2026-10-09T18:00:00.5Z",
+            "2026-10-16T17:59:59.9Z",
+            "2026-10-16T18:00:00Z",
+            "2026-10-16T18:01:00.25Z",
+        ] {
+            insert_snapshot(&pool, &m, at(taken_at)).await;
+        }
+        insert_snapshot(&pool, &other_tier, at("2026-10-09T18:00:00Z")).await;
+
+        prune_snapshots(&pool, &m.id, at(&m.start_time)).await;
+
+        let kept: Vec<String> = sqlx::query_scalar(
+            "SELECT taken_at FROM match_snapshots WHERE match_id = '1-1' ORDER BY taken_at",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            kept,
+            [
+                at("2026-10-16T18:00:00Z").to_rfc3339(),
+                at("2026-10-16T18:01:00.25Z").to_rfc3339()
+            ]
+        );
+        let others: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM match_snapshots WHERE match_id = '1-2'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(others, 1);
+    }
+
+    #[tokio::test]
+    async fn move_counts_are_not_limited_like_the_listing() {
+        let pool = test_pool().await;
+        let change = |i: usize, kind, from: Option<&str>, to: Option<&str>, at: &str| GuildMove {
+            guild_id: format!("guild-{i}"),
+            guild_name: None,
+            kind,
+            from_team: from.map(str::to_string),
+            to_team: to.map(str::to_string),
+            occurred_at: at.to_string(),
+        };
+        let reset = "2026-10-16T18:00:00+00:00";
+        let after = "2026-10-16T18:05:00+00:00";
+        let mut moves: Vec<GuildMove> = (0..1200)
+            .map(|i| change(i, MoveKind::Joined, None, Some("11001"), after))
+            .collect();
+        moves.extend(
+            (0..900).map(|i| change(i, MoveKind::Switched, Some("11001"), Some("11002"), after)),
+        );
+        moves.extend((0..900).map(|i| change(i, MoveKind::Left, Some("11001"), None, after)));
+        // Last week and another team
+        moves.push(change(
+            0,
+            MoveKind::Joined,
+            None,
+            Some("11001"),
+            "2026-10-10T00:00:00+00:00",
+        ));
+        moves.push(change(0, MoveKind::Joined, None, Some("11003"), after));
+        insert_guild_moves(&pool, &moves).await;
+
+        let listed = get_guild_moves(&pool, Some("11001"), Some(reset), None, 2000)
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 2000);
+        assert_eq!(
+            get_guild_move_counts(&pool, "11001", reset).await.unwrap(),
+            (1200, 1800, 3000)
+        );
+        assert_eq!(
+            get_guild_move_counts(&pool, "11002", reset).await.unwrap(),
+            (900, 0, 900)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+#![warn(clippy::pedantic)]
+
+use std::{cmp::Reverse, fmt::Write, sync::Arc};
+
+use axum::{
+    Router,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::data::{Data, MatchColor, MatchData};
+
+// Matches the team colors of the main page
+pub const RED: &str = "214,34,34";
+pub const GREEN: &str = "23,191,21";
+pub const BLUE: &str = "18,77,204";
+
+// The widget reloads itself, data changes at most every few seconds anyway
+const REFRESH_SECONDS: u32 = 60;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Size {
+    Small,
+    #[default]
+    Medium,
+    Large,
+}
+
+#[derive(Deserialize)]
+struct EmbedQuery {
+    #[serde(default)]
+    theme: Theme,
+    #[serde(default)]
+    size: Size,
+}
+
+pub fn router(cache: Arc<RwLock<Data>>) -> Router<()> {
+    Router::new()
+        .route("/embed/tier/{tier}", get(tier_widget))
+        .with_state(cache)
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Teams with their VP and color, leader first
+pub fn ranked_teams(m: &MatchData) -> Vec<(&MatchColor, u32, &'static str)> {
+    let mut teams: Vec<_> = [(&m.red, RED), (&m.green, GREEN), (&m.blue, BLUE)]
+        .into_iter()
+        .map(|(team, rgb)| (team, team.victory_points.parse().unwrap_or(0), rgb))
+        .collect();
+    teams.sort_by_key(|(_, vp, _)| Reverse(*vp));
+    teams
+}
+
+const fn theme_css(theme: Theme) -> &'static str {
+    match theme {
+        Theme::Dark => "--bg:#121212;--panel:#1e1e1e;--text:#e0e0e0;--muted:#999;--border:#333;",
+        Theme::Light => "--bg:#fff;--panel:#f2f2f2;--text:#1a1a1a;--muted:#666;--border:#ddd;",
+    }
+}
+
+const fn font_size(size: Size) -> u32 {
+    match size {
+        Size::Small => 12,
+        Size::Medium => 14,
+        Size::Large => 18,
+    }
+}
+
+async fn tier_widget(
+    State(cache): State<Arc<RwLock<Data>>>,
+    Path(tier): Path<usize>,
+    Query(query): Query<EmbedQuery>,
+) -> Response {
+    let data = cache.read().await;
+    let Some(m) = tier
+        .checked_sub(1)
+        .and_then(|index| data.matches.get(&index))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let teams = ranked_teams(m);
+    let max_vp = teams.first().map_or(0, |(_, vp, _)| *vp);
+
+    let mut rows = String::new();
+    for (team, vp, rgb) in teams {
+        let width = if max_vp == 0 {
+            0
+        } else {
+            u64::from(vp) * 100 / u64::from(max_vp)
+        };
+        let _ = write!(
+            rows,
+            r#"<div class="team"><div class="line"><span class="name">{name}</span><span class="vp">{vp} VP <small>+{ppt}</small></span></div><div class="bar"><div style="width:{width}%;background:rgb({rgb})"></div></div></div>"#,
+            name = escape_html(&team.team_name),
+            ppt = team.points_per_tick,
+        );
+    }
+    let skirmishes_remaining = m.skirmishes_remaining;
+    drop(data);
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="UTF-8">
+<meta http-equiv="refresh" content="{REFRESH_SECONDS}">
+<title>Tier {tier}</title>
+<style>
+:root {{ {theme} }}
+body {{ margin: 0; font: {font}px Arial, sans-serif; background: var(--bg); color: var(--text); }}
+.board {{ padding: 0.6em 0.8em; border: 1px solid var(--border); border-radius: 8px; background: var(--panel); }}
+.title {{ display: flex; justify-content: space-between; font-weight: bold; margin-bottom: 0.4em; }}
+.title small, .vp small, .source {{ color: var(--muted); font-weight: normal; }}
+.team {{ margin: 0.35em 0; }}
+.line {{ display: flex; justify-content: space-between; gap: 1em; }}
+.name {{ font-weight: bold; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }}
+.vp {{ white-space: nowrap; }}
+.bar {{ height: 0.5em; border-radius: 0.25em; background: var(--border); overflow: hidden; }}
+.bar div {{ height: 100%; }}
+.source {{ display: block; margin-top: 0.4em; font-size: 0.8em; text-align: right; text-decoration: none; }}
+</style>
+</head>
+<body>
+<div class="board">
+<div class="title"><span>Tier {tier}</span><small>{skirmishes_remaining} skirmishes left</small></div>
+{rows}
+<a class="source" href="/" target="_blank" rel="noopener">WvW Overview</a>
+</div>
+</body>
+</html>
+"#,
+        theme = theme_css(query.theme),
+        font = font_size(query.size),
+    );
+
+    ([(header::CACHE_CONTROL, "max-age=30")], Html(html)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_html_escapes_markup_and_quotes() {
+        assert_eq!(
+            escape_html(r#"<b>"Tom" & 'Jerry'</b>"#),
+            "&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;"
+        );
+        assert_eq!(escape_html("Ärger [ÄRG]"), "Ärger [ÄRG]");
+        assert_eq!(escape_html(""), "");
+    }
+}
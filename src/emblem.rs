@@ -0,0 +1,400 @@
+#![warn(clippy::pedantic)]
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use sqlx::SqlitePool;
+
+use crate::{
+    data::{APIEndpoint, Color, Emblem, EmblemLayers},
+    database::{
+        get_emblem_colors, get_emblem_image, get_emblem_layers, get_guild_emblem,
+        upsert_emblem_colors, upsert_emblem_image, upsert_emblem_layers,
+    },
+    rate_limiter::{ApiError, ApiQueue, Priority},
+};
+
+// Layer images from the render service are 256x256
+const SIZE: u32 = 256;
+// A composed emblem embeds its layers, usually a few hundred KB
+const MAX_CACHED_EMBLEMS: usize = 512;
+
+struct Rendered {
+    // Hash of the emblem definition the SVG was composed from
+    hash: u64,
+    svg: Bytes,
+}
+
+// Keyed by guild id
+static RENDERED: LazyLock<Mutex<HashMap<String, Rendered>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+pub enum EmblemError {
+    Database(sqlx::Error),
+    Api(ApiError),
+}
+
+impl From<sqlx::Error> for EmblemError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl From<ApiError> for EmblemError {
+    fn from(err: ApiError) -> Self {
+        Self::Api(err)
+    }
+}
+
+async fn layers(
+    pool: &SqlitePool,
+    api_queue: &ApiQueue,
+    kind: &str,
+    id: u32,
+) -> Result<Vec<String>, EmblemError> {
+    if let Some(layers) = get_emblem_layers(pool, kind, id).await? {
+        return Ok(layers);
+    }
+
+    let end_point = if kind == "background" {
+        APIEndpoint::EmblemBackground(id)
+    } else {
+        APIEndpoint::EmblemForeground(id)
+    };
+
+    let definition = api_queue
+        .enqueue_checked::<EmblemLayers>(&end_point, Priority::Low)
+        .await?;
+    upsert_emblem_layers(pool, kind, definition.id, &definition.layers).await?;
+    Ok(definition.layers)
+}
+
+async fn colors(
+    pool: &SqlitePool,
+    api_queue: &ApiQueue,
+    ids: &[u32],
+) -> Result<HashMap<u32, [u8; 3]>, EmblemError> {
+    let mut colors = get_emblem_colors(pool, ids).await?;
+
+    let missing: Vec<u32> = ids
+        .iter()
+        .filter(|id| !colors.contains_key(id))
+        .copied()
+        .collect();
+
+    if !missing.is_empty() {
+        let fetched = api_queue
+            .enqueue_checked::<Vec<Color>>(&APIEndpoint::Colors(missing), Priority::Low)
+            .await?;
+        upsert_emblem_colors(pool, &fetched).await?;
+        colors.extend(fetched.into_iter().map(|c| (c.id, c.cloth.rgb)));
+    }
+
+    Ok(colors)
+}
+
+async fn image(pool: &SqlitePool, api_queue: &ApiQueue, url: &str) -> Result<(), EmblemError> {
+    if get_emblem_image(pool, url).await?.is_none() {
+        let data = api_queue
+            .enqueue_bytes(&APIEndpoint::Render(url.to_string()), Priority::Low)
+            .await?;
+        upsert_emblem_image(pool, url, &data).await?;
+    }
+    Ok(())
+}
+
+fn color_ids(emblem: &Emblem) -> Vec<u32> {
+    emblem
+        .background
+        .colors
+        .iter()
+        .chain(&emblem.foreground.colors)
+        .copied()
+        .collect()
+}
+
+// The first foreground layer is an untinted preview, the others map to the two dye slots
+fn visible_foreground(layers: &[String]) -> &[String] {
+    if layers.len() > 2 {
+        &layers[1..]
+    } else {
+        layers
+    }
+}
+
+// Fetches whatever the emblem needs that isn't stored yet, parts are shared between guilds
+pub async fn store_emblem_parts(
+    pool: &SqlitePool,
+    api_queue: &ApiQueue,
+    emblem: &Emblem,
+) -> Result<(), EmblemError> {
+    let background = layers(pool, api_queue, "background", emblem.background.id).await?;
+    let foreground = layers(pool, api_queue, "foreground", emblem.foreground.id).await?;
+    colors(pool, api_queue, &color_ids(emblem)).await?;
+
+    for url in background.iter().chain(visible_foreground(&foreground)) {
+        image(pool, api_queue, url).await?;
+    }
+    Ok(())
+}
+
+fn flip_transform(flags: &[String], part: &str) -> &'static str {
+    let horizontal = flags.iter().any(|f| *f == format!("Flip{part}Horizontal"));
+    let vertical = flags.iter().any(|f| *f == format!("Flip{part}Vertical"));
+
+    match (horizontal, vertical) {
+        (true, true) => "translate(256 256) scale(-1 -1)",
+        (true, false) => "translate(256 0) scale(-1 1)",
+        (false, true) => "translate(0 256) scale(1 -1)",
+        (false, false) => "",
+    }
+}
+
+// Multiplies the greyscale layer with the dye color
+fn tint_filter(id: &str, [r, g, b]: [u8; 3]) -> String {
+    let [r, g, b] = [r, g, b].map(|c| f32::from(c) / 255.0);
+    format!(
+        r#"<filter id="{id}" color-interpolation-filters="sRGB"><feColorMatrix type="matrix" values="{r:.3} 0 0 0 0 0 {g:.3} 0 0 0 0 0 {b:.3} 0 0 0 0 0 1 0"/></filter>"#
+    )
+}
+
+fn emblem_hash(emblem: &Emblem) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    emblem.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn cache_rendered(guild_id: &str, hash: u64, svg: Bytes) {
+    let mut rendered = RENDERED.lock().unwrap();
+    if rendered.len() >= MAX_CACHED_EMBLEMS && !rendered.contains_key(guild_id) {
+        // Any entry will do, popular emblems get composed again on their next request
+        if let Some(evicted) = rendered.keys().next().cloned() {
+            rendered.remove(&evicted);
+        }
+    }
+    rendered.insert(guild_id.to_string(), Rendered { hash, svg });
+    drop(rendered);
+}
+
+// Composed once per guild and emblem, a changed emblem has a different hash.
+// Only stored parts are used, None until guild ingestion fetched all of them.
+pub async fn render_guild_emblem(
+    pool: &SqlitePool,
+    guild_id: &str,
+) -> Result<Option<Bytes>, sqlx::Error> {
+    let Some(emblem) = get_guild_emblem(pool, guild_id).await? else {
+        return Ok(None);
+    };
+
+    let hash = emblem_hash(&emblem);
+    let cached = RENDERED
+        .lock()
+        .unwrap()
+        .get(guild_id)
+        .filter(|rendered| rendered.hash == hash)
+        .map(|rendered| rendered.svg.clone());
+    if cached.is_some() {
+        return Ok(cached);
+    }
+
+    let Some(svg) = compose(pool, &emblem).await? else {
+        return Ok(None);
+    };
+    let svg = Bytes::from(svg);
+    cache_rendered(guild_id, hash, svg.clone());
+    Ok(Some(svg))
+}
+
+async fn compose(pool: &SqlitePool, emblem: &Emblem) -> Result<Option<String>, sqlx::Error> {
+    let (Some(background), Some(foreground)) = (
+        get_emblem_layers(pool, "background", emblem.background.id).await?,
+        get_emblem_layers(pool, "foreground", emblem.foreground.id).await?,
+    ) else {
+        return Ok(None);
+    };
+
+    let color_ids = color_ids(emblem);
+    let colors = get_emblem_colors(pool, &color_ids).await?;
+    if color_ids.iter().any(|id| !colors.contains_key(id)) {
+        return Ok(None);
+    }
+
+    let mut defs = String::new();
+    let mut body = String::new();
+
+    for (part, urls, part_colors, flip) in [
+        (
+            "bg",
+            &background[..],
+            &emblem.background.colors,
+            flip_transform(&emblem.flags, "Background"),
+        ),
+        (
+            "fg",
+            visible_foreground(&foreground),
+            &emblem.foreground.colors,
+            flip_transform(&emblem.flags, "Foreground"),
+        ),
+    ] {
+        if flip.is_empty() {
+            body.push_str("<g>");
+        } else {
+            let _ = write!(body, r#"<g transform="{flip}">"#);
+        }
+        for (i, url) in urls.iter().enumerate() {
+            let filter = part_colors
+                .get(i.min(part_colors.len().saturating_sub(1)))
+                .and_then(|id| colors.get(id))
+                .map(|rgb| {
+                    let id = format!("{part}{i}");
+                    defs.push_str(&tint_filter(&id, *rgb));
+                    format!(r#" filter="url(#{id})""#)
+                })
+                .unwrap_or_default();
+
+            let Some(data) = get_emblem_image(pool, url).await? else {
+                return Ok(None);
+            };
+            let _ = write!(
+                body,
+                r#"<image href="data:image/png;base64,{}" width="{SIZE}" height="{SIZE}"{filter}/>"#,
+                STANDARD.encode(data)
+            );
+        }
+        body.push_str("</g>");
+    }
+
+    Ok(Some(format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{SIZE}" height="{SIZE}" viewBox="0 0 {SIZE} {SIZE}"><defs>{defs}</defs>{body}</svg>"#
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{ColorMaterial, EmblemPart},
+        database::test_pool,
+    };
+
+    fn emblem(background: u32) -> Emblem {
+        Emblem {
+            background: EmblemPart {
+                id: background,
+                colors: vec![1],
+            },
+            foreground: EmblemPart {
+                id: 7,
+                colors: vec![2, 3],
+            },
+            flags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn changed_emblem_changes_hash() {
+        assert_eq!(emblem_hash(&emblem(1)), emblem_hash(&emblem(1)));
+        assert_ne!(emblem_hash(&emblem(1)), emblem_hash(&emblem(2)));
+    }
+
+    #[test]
+    fn rendered_cache_is_bounded() {
+        for i in 0..MAX_CACHED_EMBLEMS + 10 {
+            cache_rendered(&format!("bounded-{i}"), 0, Bytes::from_static(b"<svg/>"));
+        }
+        assert!(RENDERED.lock().unwrap().len() <= MAX_CACHED_EMBLEMS);
+    }
+
+    #[test]
+    fn flips() {
+        let flags = vec![
+            "FlipBackgroundHorizontal".to_string(),
+            "FlipForegroundVertical".to_string(),
+            "FlipForegroundHorizontal".to_string(),
+        ];
+        assert_eq!(
+            flip_transform(&flags, "Background"),
+            "translate(256 0) scale(-1 1)"
+        );
+        assert_eq!(
+            flip_transform(&flags, "Foreground"),
+            "translate(256 256) scale(-1 -1)"
+        );
+        assert_eq!(flip_transform(&[], "Background"), "");
+    }
+
+    #[test]
+    fn tint_scales_channels() {
+        let filter = tint_filter("bg0", [255, 0, 51]);
+        assert!(filter.contains(r#"id="bg0""#));
+        assert!(filter.contains(r#"values="1.000 0 0 0 0 0 0.000 0 0 0 0 0 0.200 0 0 0 0 0 1 0""#));
+    }
+
+    #[tokio::test]
+    async fn composes_only_from_stored_parts() {
+        let pool = test_pool().await;
+        sqlx::query("INSERT INTO guild_emblems (guild_id, emblem) VALUES ('stored', ?)")
+            .bind(serde_json::to_string(&emblem(1)).unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Nothing fetched yet
+        assert!(
+            render_guild_emblem(&pool, "stored")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            render_guild_emblem(&pool, "unknown")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        upsert_emblem_layers(&pool, "background", 1, &["bg.png".to_string()])
+            .await
+            .unwrap();
+        upsert_emblem_layers(
+            &pool,
+            "foreground",
+            7,
+            &["preview.png", "fg1.png", "fg2.png"].map(str::to_string),
+        )
+        .await
+        .unwrap();
+        let colors: Vec<Color> = (1..=3)
+            .map(|id| Color {
+                id,
+                cloth: ColorMaterial { rgb: [255, 0, 0] },
+            })
+            .collect();
+        upsert_emblem_colors(&pool, &colors).await.unwrap();
+        for url in ["bg.png", "fg1.png"] {
+            upsert_emblem_image(&pool, url, b"png").await.unwrap();
+        }
+
+        // One layer image is still missing
+        assert!(
+            render_guild_emblem(&pool, "stored")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        upsert_emblem_image(&pool, "fg2.png", b"png").await.unwrap();
+        let svg = render_guild_emblem(&pool, "stored").await.unwrap().unwrap();
+        let svg = std::str::from_utf8(&svg).unwrap();
+        // The untinted preview layer is left out
+        assert_eq!(svg.matches("<image ").count(), 3);
+        assert!(svg.contains(r#"filter="url(#fg1)""#));
+    }
+}
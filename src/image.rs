@@ -0,0 +1,250 @@
+#![warn(clippy::pedantic)]
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use bytes::Bytes;
+use resvg::{tiny_skia, usvg};
+use tokio::sync::RwLock;
+
+use crate::{
+    data::{Data, MatchData},
+    embed::{escape_html, ranked_teams},
+    push::{DataVersion, Updates, content_etag},
+    tasks::log_error,
+};
+
+pub const WIDTH: u32 = 600;
+pub const HEIGHT: u32 = 250;
+const IMAGE_CACHE_CONTROL: &str = "max-age=60";
+
+// Loading the system fonts takes a while, so it is done once on the first PNG
+static FONTS: LazyLock<Arc<usvg::fontdb::Database>> = LazyLock::new(|| {
+    let mut fonts = usvg::fontdb::Database::new();
+    fonts.load_system_fonts();
+    Arc::new(fonts)
+});
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Format {
+    Svg,
+    Png,
+}
+
+impl Format {
+    const fn content_type(self) -> &'static str {
+        match self {
+            Self::Svg => "image/svg+xml",
+            Self::Png => "image/png",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rendered {
+    // The data version the image was drawn from
+    data_etag: String,
+    etag: String,
+    body: Bytes,
+}
+
+pub struct ImageState {
+    cache: Arc<RwLock<Data>>,
+    updates: Updates,
+    rendered: Mutex<HashMap<(usize, Format), Rendered>>,
+}
+
+pub fn router(cache: Arc<RwLock<Data>>, updates: Updates) -> Router<()> {
+    Router::new()
+        .route("/image/tier/{file}", get(tier_image))
+        .with_state(Arc::new(ImageState {
+            cache,
+            updates,
+            rendered: Mutex::new(HashMap::new()),
+        }))
+}
+
+// `1.svg` -> (1, Svg)
+fn parse_file(file: &str) -> Option<(usize, Format)> {
+    let (tier, extension) = file.split_once('.')?;
+    let format = match extension {
+        "svg" => Format::Svg,
+        "png" => Format::Png,
+        _ => return None,
+    };
+    Some((tier.parse().ok()?, format))
+}
+
+fn render_svg(tier: usize, m: &MatchData, version: &DataVersion) -> String {
+    let teams = ranked_teams(m);
+    let total_vp: u32 = teams.iter().map(|(_, vp, _)| vp).sum();
+
+    let mut rows = String::new();
+    for (i, (team, vp, rgb)) in teams.into_iter().enumerate() {
+        let y = 80 + i * 50;
+        let share = if total_vp == 0 {
+            0.0
+        } else {
+            f64::from(vp) / f64::from(total_vp)
+        };
+        let _ = write!(
+            rows,
+            r##"<text x="24" y="{y}" class="name">{name}</text><text x="576" y="{y}" class="vp" text-anchor="end">{vp} VP  +{ppt}</text><rect x="24" y="{bar_y}" width="552" height="12" rx="6" fill="#333"/><rect x="24" y="{bar_y}" width="{bar_width:.1}" height="12" rx="6" fill="rgb({rgb})"/>"##,
+            name = escape_html(&team.team_name),
+            ppt = team.points_per_tick,
+            bar_y = y + 10,
+            bar_width = 552.0 * share,
+        );
+    }
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}">
+<style>
+text {{ font-family: Arial, 'DejaVu Sans', sans-serif; fill: #e0e0e0; }}
+.title {{ font-size: 22px; font-weight: bold; }}
+.name {{ font-size: 17px; font-weight: bold; }}
+.vp {{ font-size: 15px; }}
+.muted {{ font-size: 13px; fill: #999; }}
+</style>
+<rect width="{WIDTH}" height="{HEIGHT}" rx="12" fill="#1e1e1e" stroke="#333"/>
+<text x="24" y="40" class="title">Tier {tier}</text>
+<text x="576" y="40" class="muted" text-anchor="end">{skirmishes} skirmishes left</text>
+{rows}
+<text x="24" y="232" class="muted">Updated {updated}</text>
+<text x="576" y="232" class="muted" text-anchor="end">WvW Overview</text>
+</svg>
+"##,
+        skirmishes = m.skirmishes_remaining,
+        updated = version.modified.format("%Y-%m-%d %H:%M UTC"),
+    )
+}
+
+fn render_png(svg: &str) -> Option<Vec<u8>> {
+    let options = usvg::Options {
+        fontdb: FONTS.clone(),
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_str(svg, &options)
+        .map_err(log_error)
+        .ok()?;
+    let mut pixmap = tiny_skia::Pixmap::new(WIDTH, HEIGHT)?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap.encode_png().map_err(log_error).ok()
+}
+
+async fn render(
+    state: &ImageState,
+    tier: usize,
+    format: Format,
+    version: &DataVersion,
+) -> Option<Bytes> {
+    let svg = {
+        let data = state.cache.read().await;
+        let m = tier
+            .checked_sub(1)
+            .and_then(|index| data.matches.get(&index))?;
+        render_svg(tier, m, version)
+    };
+
+    match format {
+        Format::Svg => Some(Bytes::from(svg)),
+        // Rasterizing is CPU heavy, keep it off the async workers
+        Format::Png => tokio::task::spawn_blocking(move || render_png(&svg))
+            .await
+            .map_err(log_error)
+            .ok()
+            .flatten()
+            .map(Bytes::from),
+    }
+}
+
+async fn tier_image(
+    State(state): State<Arc<ImageState>>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((tier, format)) = parse_file(&file) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let version = state.updates.borrow().clone();
+
+    // Nothing published yet, the first cache build is still running
+    if version.etag.is_empty() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    let cached = state
+        .rendered
+        .lock()
+        .unwrap()
+        .get(&(tier, format))
+        .filter(|rendered| rendered.data_etag == version.etag)
+        .cloned();
+
+    let rendered = if let Some(rendered) = cached {
+        rendered
+    } else {
+        let Some(body) = render(&state, tier, format, &version).await else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let rendered = Rendered {
+            data_etag: version.etag.clone(),
+            etag: content_etag(&body),
+            body,
+        };
+        state
+            .rendered
+            .lock()
+            .unwrap()
+            .insert((tier, format), rendered.clone());
+        rendered
+    };
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|if_none_match| if_none_match == rendered.etag);
+
+    let response = Response::builder()
+        .header(header::ETAG, &rendered.etag)
+        .header(header::LAST_MODIFIED, version.last_modified())
+        .header(header::CACHE_CONTROL, IMAGE_CACHE_CONTROL);
+
+    if not_modified {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    response
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(Body::from(rendered.body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_select_tier_and_format() {
+        assert_eq!(parse_file("1.svg"), Some((1, Format::Svg)));
+        assert_eq!(parse_file("12.png"), Some((12, Format::Png)));
+        assert!(parse_file("1.gif").is_none());
+        assert!(parse_file("one.png").is_none());
+        assert!(parse_file("1").is_none());
+    }
+}
@@ -0,0 +1,203 @@
+#![warn(clippy::pedantic)]
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+
+// Idle buckets are only swept once the map grows past this
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// The address a request is attributed to, set by `limit_requests` for the handlers behind it
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct Limits {
+    rate: f64,
+    burst: f64,
+    trusted_proxies: Vec<IpAddr>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    concurrency: Arc<Semaphore>,
+}
+
+impl Limits {
+    pub fn new(rate: u32, burst: u32, max_concurrent: usize, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+            trusted_proxies,
+            buckets: Mutex::new(HashMap::new()),
+            concurrency: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+
+    // Walks X-Forwarded-For from the right and stops at the first hop we don't run ourselves
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.trusted_proxies.contains(ip))
+            .or_else(|| forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+
+    // Ok when a token was taken, Err holds the seconds until the next one
+    fn take(&self, ip: IpAddr) -> Result<(), f64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_CLIENTS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                now.duration_since(b.updated)
+                    .as_secs_f64()
+                    .mul_add(rate, b.tokens)
+                    < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = now
+            .duration_since(bucket.updated)
+            .as_secs_f64()
+            .mul_add(self.rate, bucket.tokens)
+            .min(self.burst);
+        bucket.updated = now;
+
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - bucket.tokens) / self.rate)
+        };
+        drop(buckets);
+        result
+    }
+}
+
+fn too_many_requests(retry_after: f64) -> Response {
+    // Retry-After only takes whole seconds
+    let seconds = format!("{:.0}", retry_after.ceil().clamp(1.0, 3600.0));
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds)],
+    )
+        .into_response()
+}
+
+pub async fn limit_requests(
+    State(limits): State<Arc<Limits>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let ip = limits.client_ip(peer.ip(), req.headers());
+
+    if let Err(retry_after) = limits.take(ip) {
+        return too_many_requests(retry_after);
+    }
+
+    // Streams (SSE, WebSocket) give their permit back once the response head is sent
+    let Ok(_permit) = limits.concurrency.clone().try_acquire_owned() else {
+        return too_many_requests(1.0);
+    };
+
+    req.extensions_mut().insert(ClientIp(ip));
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn forwarded_for(hops: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for hop in hops {
+            headers.append("x-forwarded-for", hop.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_for_is_only_read_from_trusted_proxies() {
+        let limits = Limits::new(1, 1, 1, vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+        let headers = forwarded_for(&["1.1.1.1, 2.2.2.2"]);
+
+        // Anyone else could claim any address
+        assert_eq!(limits.client_ip(ip("3.3.3.3"), &headers), ip("3.3.3.3"));
+        // The rightmost hop is the one our proxy saw
+        assert_eq!(limits.client_ip(ip("10.0.0.1"), &headers), ip("2.2.2.2"));
+        // Our own proxies in the chain are skipped, across repeated headers too
+        let chained = forwarded_for(&["1.1.1.1", "2.2.2.2, 10.0.0.2"]);
+        assert_eq!(limits.client_ip(ip("10.0.0.1"), &chained), ip("2.2.2.2"));
+        // Only proxies in the chain, the leftmost is the best guess
+        let internal = forwarded_for(&["10.0.0.2"]);
+        assert_eq!(limits.client_ip(ip("10.0.0.1"), &internal), ip("10.0.0.2"));
+        // Garbage or nothing falls back to the proxy itself
+        let garbage = forwarded_for(&["unknown"]);
+        assert_eq!(limits.client_ip(ip("10.0.0.1"), &garbage), ip("10.0.0.1"));
+        assert_eq!(
+            limits.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn bucket_allows_the_burst_then_asks_to_wait() {
+        let limits = Limits::new(1, 3, 1, Vec::new());
+        let client = ip("1.1.1.1");
+
+        for _ in 0..3 {
+            assert!(limits.take(client).is_ok());
+        }
+        let retry_after = limits.take(client).unwrap_err();
+        assert!(retry_after > 0.9 && retry_after <= 1.0, "{retry_after}");
+
+        // Every client has its own bucket
+        assert!(limits.take(ip("2.2.2.2")).is_ok());
+    }
+
+    #[test]
+    fn retry_after_is_whole_seconds() {
+        for (retry_after, expected) in [(0.01, "1"), (1.2, "2"), (90_000.0, "3600")] {
+            let response = too_many_requests(retry_after);
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers()[header::RETRY_AFTER], expected);
+        }
+    }
+}
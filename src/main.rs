@@ -1,7 +1,7 @@
 #![warn(clippy::pedantic)]
 
 use std::{
-    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
@@ -9,30 +9,57 @@ use std::{
 use axum::{
     Router,
     body::Body,
-    extract::State,
-    http::{Request, Response},
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, header},
+    middleware,
     response::{Html, IntoResponse},
     routing::get,
 };
 use reqwest::StatusCode;
-use tokio::sync::RwLock;
-use tower_http::compression::CompressionLayer;
+use tokio::sync::{RwLock, broadcast, watch};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    timeout::TimeoutLayer,
+};
 
 use crate::{
+    access::{Access, AccessMode, KeyCommand, check_access, issue_stream_token, run_key_command},
     data::Data,
     database::init_db,
+    embed::{escape_html, ranked_teams},
+    limits::{Limits, limit_requests},
+    push::{EVENT_CAPACITY, PushState, Updates, data_events, entity_tags, version_etag, ws},
     rate_limiter::ApiQueue,
-    tasks::{run_mateches_cache_updater, start_update_loops},
+    search::SearchIndex,
+    tasks::{TRACKED_GUILD, run_mateches_cache_updater, start_update_loops},
 };
-use clap::{Parser, command};
+use clap::{Parser, Subcommand};
 
+mod access;
+mod api;
 mod data;
 mod database;
+mod embed;
+mod emblem;
+mod image;
+mod limits;
+mod prediction;
+mod push;
 mod rate_limiter;
+mod search;
 mod tasks;
 
 const INDEX_HTML: &str = include_str!("../static/frontend/index.html");
+// Replaced with the Open Graph and Twitter card tags on every request
+const OG_PLACEHOLDER: &str = "<!-- og-tags -->";
 const FAVICON_SVG: &str = include_str!("../static/frontend/favicons/swords.svg");
+const JSON_PATCH: &str = "application/json-patch+json";
+// Data changes at most every few seconds, caches may serve a stale copy while they revalidate
+const DATA_CACHE_CONTROL: &str = "max-age=2, stale-while-revalidate=30";
+// The body depends on both, 304s included
+const DATA_VARY: &str = "Accept, Accept-Encoding";
 
 #[derive(Parser, Debug)]
 #[command(name = "WvW Overview")]
@@ -45,6 +72,68 @@ struct Args {
     /// Port to bind to
     #[arg(long, default_value = "12345")]
     port: u16,
+
+    /// Who may read /data/ and /api
+    #[arg(long, value_enum, default_value_t = AccessMode::Anonymous)]
+    access: AccessMode,
+
+    /// Requests per minute and IP for clients without an API key
+    #[arg(long, default_value = "120")]
+    anonymous_quota: u32,
+
+    /// Sustained requests per second and client IP
+    #[arg(long, default_value = "10")]
+    rate_limit: u32,
+
+    /// Requests a client IP may make in a burst above the sustained rate
+    #[arg(long, default_value = "40")]
+    rate_burst: u32,
+
+    /// Requests handled at the same time across all clients
+    #[arg(long, default_value = "256")]
+    max_concurrent: usize,
+
+    /// Seconds before a request is aborted
+    #[arg(long, default_value = "30")]
+    request_timeout: u64,
+
+    /// Largest accepted request body in bytes
+    #[arg(long, default_value = "16384")]
+    max_body: usize,
+
+    /// Proxy allowed to set X-Forwarded-For, can be given multiple times
+    #[arg(long)]
+    trusted_proxy: Vec<IpAddr>,
+
+    /// Address the site is reached at, like `https://wvw.example.com`, for absolute links in link previews
+    #[arg(long)]
+    public_url: Option<String>,
+
+    /// Origin allowed to call /api and /data/ from a browser, `*` for any, can be given multiple times
+    #[arg(long)]
+    cors_origin: Vec<String>,
+
+    /// Methods allowed for cross-origin requests
+    #[arg(long, value_delimiter = ',', default_value = "GET,HEAD")]
+    cors_methods: Vec<Method>,
+
+    /// Request headers allowed for cross-origin requests
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "accept,authorization,x-api-key,if-none-match,if-modified-since,last-event-id"
+    )]
+    cors_headers: Vec<HeaderName>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage API keys
+    #[command(subcommand)]
+    Keys(KeyCommand),
 }
 
 #[tokio::main]
@@ -53,12 +142,33 @@ async fn main() {
 
     let addr = format!("{}:{}", args.ip, args.port);
     let pool = init_db().await.unwrap();
+
+    if let Some(Command::Keys(command)) = args.command {
+        run_key_command(&pool, command).await.unwrap();
+        return;
+    }
+
     let api_queue = Arc::new(ApiQueue::new(Duration::from_millis(210)));
 
     start_update_loops(&pool, &api_queue);
 
     let cache: Arc<RwLock<Data>> = Arc::new(RwLock::new(Data::default()));
-    run_mateches_cache_updater(&pool, cache.clone()).await;
+    let (updates_tx, updates) = watch::channel(Arc::default());
+    let (events, _) = broadcast::channel(EVENT_CAPACITY);
+    let search = Arc::new(RwLock::new(SearchIndex::default()));
+    run_mateches_cache_updater(
+        &pool,
+        cache.clone(),
+        updates_tx,
+        events.clone(),
+        search.clone(),
+    )
+    .await;
+
+    let access = Arc::new(Access::new(args.access, pool.clone(), args.anonymous_quota));
+    let access_layer = middleware::from_fn_with_state(access.clone(), check_access);
+
+    let cors = cors_layer(&args);
 
     let compression = CompressionLayer::new()
         .gzip(true)
@@ -68,29 +178,221 @@ async fn main() {
 
     let root_route: Router<()> = Router::new()
         .route("/", get(index))
+        .with_state(IndexState {
+            cache: cache.clone(),
+            public_url: args.public_url.clone(),
+            trusted_proxies: args.trusted_proxy.clone(),
+        })
         .layer(compression.clone());
 
     let data_route: Router<()> = Router::new()
         .route("/data/", get(data))
-        .with_state(cache.clone())
+        .route("/data/events", get(data_events))
+        .route("/ws", get(ws))
+        .with_state(PushState {
+            updates: updates.clone(),
+            events,
+        })
+        .layer(access_layer.clone())
         .layer(compression.clone());
 
+    let token_route: Router<()> = Router::new()
+        .route("/data/token", get(issue_stream_token))
+        .with_state(access)
+        .layer(access_layer.clone());
+
     let favicon_route: Router<()> = Router::new()
         .route("/favicon.svg", get(favicon))
         .route("/favicon.ico", get(favicon))
         .layer(compression.clone());
 
-    let app = Router::new()
-        .merge(root_route)
-        .merge(data_route)
-        .merge(favicon_route);
+    // Meant to be shown on other sites, so readable without a key like the page itself
+    let embed_route: Router<()> = embed::router(cache.clone()).layer(compression.clone());
+
+    // Fetched by link preview crawlers, which never send a key
+    let image_route: Router<()> = image::router(cache.clone(), updates).layer(compression.clone());
+
+    let api_route: Router<()> = api::router(pool.clone(), cache.clone(), search)
+        .layer(access_layer)
+        .layer(compression.clone());
+
+    // One instance for both halves, a client has a single budget across all routes
+    let limits = Arc::new(Limits::new(
+        args.rate_limit,
+        args.rate_burst,
+        args.max_concurrent,
+        args.trusted_proxy.clone(),
+    ));
+
+    // CORS wraps the limits so browsers can also read the 429, 413 and 408 responses
+    let cross_origin = limited(
+        Router::new()
+            .merge(api_route)
+            .merge(data_route)
+            .merge(token_route),
+        &limits,
+        &args,
+    )
+    .layer(cors);
+
+    let same_origin = limited(
+        Router::new()
+            .merge(root_route)
+            .merge(embed_route)
+            .merge(image_route)
+            .merge(favicon_route)
+            .fallback(|| async { StatusCode::NOT_FOUND }),
+        &limits,
+        &args,
+    );
+
+    let app = cross_origin.merge(same_origin);
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+fn limited(router: Router, limits: &Arc<Limits>, args: &Args) -> Router {
+    router
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            Duration::from_secs(args.request_timeout),
+        ))
+        .layer(RequestBodyLimitLayer::new(args.max_body))
+        .layer(middleware::from_fn_with_state(
+            limits.clone(),
+            limit_requests,
+        ))
+}
+
+// Only the API and data routes get CORS headers, everything else stays same-origin.
+// Without configured origins no Access-Control-Allow-Origin is sent and browsers block as before.
+fn cors_layer(args: &Args) -> CorsLayer {
+    let origin = if args.cors_origin.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            args.cors_origin
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+
+    CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods(args.cors_methods.clone())
+        .allow_headers(args.cors_headers.clone())
+        // Delta and rate limit aware clients need to read these
+        .expose_headers([
+            HeaderName::from_static("etag"),
+            HeaderName::from_static("last-modified"),
+            HeaderName::from_static("retry-after"),
+            HeaderName::from_static("x-ratelimit-limit"),
+            HeaderName::from_static("x-ratelimit-remaining"),
+        ])
+}
+
+#[derive(Clone)]
+struct IndexState {
+    cache: Arc<RwLock<Data>>,
+    public_url: Option<String>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+async fn index(
+    State(state): State<IndexState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let from_proxy = state.trusted_proxies.contains(&peer.ip());
+    let base = base_url(state.public_url.as_deref(), from_proxy, &headers);
+
+    let tags = og_tags(&*state.cache.read().await, &base);
+    Html(INDEX_HTML.replacen(OG_PLACEHOLDER, &tags, 1))
+}
+
+// Link previews need absolute URLs, --public-url avoids trusting the request for them at all
+fn base_url(public_url: Option<&str>, from_proxy: bool, headers: &HeaderMap) -> String {
+    if let Some(public_url) = public_url {
+        return public_url.trim_end_matches('/').to_string();
+    }
+
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+    // Only our own proxy knows how the client connected
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .filter(|&proto| from_proxy && proto == "https")
+        .unwrap_or("http");
+    format!("{scheme}://{host}")
 }
 
-async fn index() -> impl IntoResponse {
-    Html(INDEX_HTML)
+fn og_tags(data: &Data, base: &str) -> String {
+    const TITLE: &str = "WvW Overview";
+
+    // The tier and standing of the tracked guild's team, if it is playing
+    let standing = data.matches.iter().find_map(|(&index, m)| {
+        ranked_teams(m)
+            .into_iter()
+            .find(|(team, _, _)| !data.our_team.is_empty() && team.team_name == data.our_team)
+            .map(|(team, vp, _)| (index + 1, team, vp))
+    });
+
+    let mut tags = vec![
+        ("og:type", "website".to_string()),
+        ("og:site_name", TITLE.to_string()),
+        ("og:title", TITLE.to_string()),
+        ("og:url", format!("{base}/")),
+        ("twitter:title", TITLE.to_string()),
+    ];
+
+    if let Some((tier, team, vp)) = standing {
+        let description = format!(
+            "{TRACKED_GUILD} is on {}, Tier {tier}, VP {vp}",
+            team.team_name
+        );
+        let image = format!("{base}/image/tier/{tier}.png");
+        tags.extend([
+            ("og:description", description.clone()),
+            ("og:image", image.clone()),
+            ("og:image:width", image::WIDTH.to_string()),
+            ("og:image:height", image::HEIGHT.to_string()),
+            ("twitter:card", "summary_large_image".to_string()),
+            ("twitter:description", description),
+            ("twitter:image", image),
+        ]);
+    } else {
+        let description = "Current WvW matchups and the guilds on each team".to_string();
+        tags.extend([
+            ("og:description", description.clone()),
+            ("twitter:card", "summary".to_string()),
+            ("twitter:description", description),
+        ]);
+    }
+
+    tags.into_iter()
+        .map(|(property, content)| {
+            // Twitter reads `name`, Open Graph `property`
+            let attribute = if property.starts_with("twitter:") {
+                "name"
+            } else {
+                "property"
+            };
+            format!(
+                r#"<meta {attribute}="{property}" content="{}">"#,
+                escape_html(&content)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 async fn favicon() -> impl IntoResponse {
@@ -100,35 +402,150 @@ async fn favicon() -> impl IntoResponse {
     )
 }
 
-async fn data(State(cache): State<Arc<RwLock<Data>>>, req: Request<Body>) -> impl IntoResponse {
-    if req.headers().get("test").is_none() {
+async fn data(State(updates): State<Updates>, req: Request<Body>) -> impl IntoResponse {
+    let version = updates.borrow().clone();
+    let etag = &version.etag;
+
+    // Nothing published yet, the first cache build is still running
+    if etag.is_empty() {
         return Response::builder()
-            .status(StatusCode::NOT_FOUND)
+            .status(StatusCode::SERVICE_UNAVAILABLE)
             .body(Body::empty())
             .unwrap();
     }
 
-    let cloned = cache.read().await.clone();
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let if_none_match = header("if-none-match");
+    let wants_patch = header("accept").is_some_and(|accept| accept.contains(JSON_PATCH));
+
+    // Pre-compressed variants already carry a Content-Encoding, the compression layer skips them
+    let variant = version.variant(header("accept-encoding").unwrap_or_default());
+
+    // If-Modified-Since only counts when the client sent no ETag
+    let not_modified = if_none_match.map_or_else(
+        || header("if-modified-since").is_some_and(|since| !version.modified_since(since)),
+        |if_none_match| {
+            push::if_none_match(if_none_match, &variant.etag)
+                // Patch clients keep the decoded JSON, the encoding they got it in doesn't matter
+                || wants_patch
+                    && entity_tags(if_none_match).any(|tag| version_etag(tag) == *etag)
+        },
+    );
 
-    let mut hasher = DefaultHasher::new();
-    cloned.hash(&mut hasher);
-    let etag = format!("\"{:x}\"", hasher.finish());
+    let last_modified = version.last_modified();
 
-    if let Some(if_none_match) = req.headers().get("if-none-match")
-        && if_none_match.to_str().unwrap_or("") == etag
-    {
+    if not_modified {
         // Data hasn't changed, return 304
         return Response::builder()
             .status(StatusCode::NOT_MODIFIED)
+            .header("ETag", &variant.etag)
+            .header("Last-Modified", &last_modified)
+            .header("Cache-Control", DATA_CACHE_CONTROL)
+            .header("Vary", DATA_VARY)
             .body(Body::empty())
             .unwrap();
     }
 
-    let json = serde_json::to_string(&cloned).unwrap();
+    // Clients that accept a patch get one from the version they hold, if it is still known
+    let patch = if_none_match
+        .filter(|_| wants_patch)
+        .and_then(|tags| entity_tags(tags).find_map(|tag| version.patch_from(&version_etag(tag))));
 
-    Response::builder()
-        .header("ETag", etag)
+    if let Some(patch) = patch {
+        // A patch only fits the version this client holds, shared caches must not reuse it
+        return Response::builder()
+            .header("ETag", etag)
+            .header("Last-Modified", &last_modified)
+            .header("Cache-Control", "no-store")
+            .header("Content-Type", JSON_PATCH)
+            .header("Vary", DATA_VARY)
+            .body(Body::from(patch))
+            .unwrap();
+    }
+
+    let mut response = Response::builder()
+        .header("ETag", &variant.etag)
+        .header("Last-Modified", &last_modified)
+        .header("Cache-Control", DATA_CACHE_CONTROL)
         .header("Content-Type", "application/json")
-        .body(Body::from(json))
-        .unwrap()
+        .header("Vary", DATA_VARY);
+    if let Some(encoding) = variant.encoding {
+        response = response.header("Content-Encoding", encoding);
+    }
+    response.body(Body::from(variant.body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::{MatchColor, MatchData};
+
+    fn headers(host: &str, proto: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, host.parse().unwrap());
+        headers.insert("x-forwarded-proto", proto.parse().unwrap());
+        headers
+    }
+
+    fn team(name: &str, vp: u32) -> MatchColor {
+        MatchColor {
+            team_name: name.to_string(),
+            victory_points: vp.to_string(),
+            ..MatchColor::default()
+        }
+    }
+
+    #[test]
+    fn base_url_prefers_the_configured_address() {
+        let forwarded = headers("evil.example", "https");
+        assert_eq!(
+            base_url(Some("https://wvw.example.com/"), true, &forwarded),
+            "https://wvw.example.com"
+        );
+        assert_eq!(base_url(None, true, &forwarded), "https://evil.example");
+        // Clients talking to us directly can't claim a scheme
+        assert_eq!(base_url(None, false, &forwarded), "http://evil.example");
+        assert_eq!(
+            base_url(None, true, &headers("a.example", "javascript")),
+            "http://a.example"
+        );
+        assert_eq!(base_url(None, false, &HeaderMap::new()), "http://localhost");
+    }
+
+    #[test]
+    fn og_tags_describe_the_tracked_team() {
+        let mut data = Data {
+            our_team: "Bava <Nisos>".to_string(),
+            ..Data::default()
+        };
+        data.matches.insert(
+            1,
+            MatchData {
+                red: team("Moogooloo", 150),
+                green: team("Bava <Nisos>", 120),
+                blue: team("Yohlon Haven", 90),
+                skirmishes_remaining: 10,
+            },
+        );
+
+        let tags = og_tags(&data, "https://wvw.example.com");
+        assert!(tags.contains(&format!(
+            r#"<meta property="og:description" content="{TRACKED_GUILD} is on Bava &lt;Nisos&gt;, Tier 2, VP 120">"#
+        )));
+        assert!(tags.contains(
+            r#"<meta property="og:image" content="https://wvw.example.com/image/tier/2.png">"#
+        ));
+        assert!(tags.contains(
+            r#"<meta name="twitter:image" content="https://wvw.example.com/image/tier/2.png">"#
+        ));
+        assert!(tags.contains(r#"<meta property="og:url" content="https://wvw.example.com/">"#));
+        assert!(tags.contains(r#"<meta name="twitter:card" content="summary_large_image">"#));
+    }
+
+    #[test]
+    fn og_tags_without_a_tracked_team_have_no_image() {
+        let tags = og_tags(&Data::default(), "http://localhost");
+        assert!(tags.contains(r#"<meta name="twitter:card" content="summary">"#));
+        assert!(!tags.contains("og:image"));
+    }
 }
@@ -0,0 +1,246 @@
+#![warn(clippy::pedantic)]
+
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use crate::{
+    data::{Confidence, MatchData, Movement, NextMatchup, Prediction, TeamPrediction, Tier},
+    tasks::SKIRMISH_VP,
+};
+
+// Largest VP difference a single skirmish can make between two teams (1st vs 3rd place)
+const MAX_VP_SWING: u32 = SKIRMISH_VP[0] - SKIRMISH_VP[2];
+
+const fn confidence(margin: u32, skirmishes_remaining: u32) -> Confidence {
+    let max_swing = skirmishes_remaining * MAX_VP_SWING;
+    if margin > max_swing {
+        Confidence::Locked
+    } else if margin * 2 > max_swing {
+        Confidence::Likely
+    } else {
+        Confidence::Contested
+    }
+}
+
+pub fn predict_movement(matches: &BTreeMap<usize, MatchData>, our_team_id: &str) -> Prediction {
+    let bottom = Tier::all().len() - 1;
+    let mut teams = Vec::new();
+
+    for (&index, m) in matches {
+        let mut standings = [&m.red, &m.green, &m.blue]
+            .map(|color| (color, color.victory_points.parse::<u32>().unwrap_or(0)));
+        standings.sort_by_key(|(_, vp)| Reverse(*vp));
+
+        let vps = standings.map(|(_, vp)| vp);
+
+        for (place, (color, vp)) in standings.into_iter().enumerate() {
+            let movement = match place {
+                0 if index > 0 => Movement::Up,
+                2 if index < bottom => Movement::Down,
+                _ => Movement::Stay,
+            };
+
+            // Distance to the nearest line that changes the movement: between 1st and 2nd
+            // unless nobody moves up, between 2nd and 3rd unless nobody moves down
+            let up_line = (index > 0).then(|| if place == 0 { vp - vps[1] } else { vps[0] - vp });
+            let down_line =
+                (index < bottom).then(|| if place == 2 { vps[1] - vp } else { vp - vps[2] });
+            let vp_margin = up_line.into_iter().chain(down_line).min();
+
+            let next_tier = match movement {
+                Movement::Up => index - 1,
+                Movement::Stay => index,
+                Movement::Down => index + 1,
+            };
+
+            teams.push(TeamPrediction {
+                team_id: color.team_id.clone(),
+                team_name: color.team_name.clone(),
+                tier: index + 1,
+                next_tier: next_tier + 1,
+                movement,
+                vp_margin,
+                confidence: vp_margin.map_or(Confidence::Locked, |margin| {
+                    confidence(margin, m.skirmishes_remaining)
+                }),
+            });
+        }
+    }
+
+    // Names are only for display, two unmapped teams would both be "Unknown"
+    let mut grouped: BTreeMap<usize, Vec<&TeamPrediction>> = BTreeMap::new();
+    for team in &teams {
+        grouped.entry(team.next_tier).or_default().push(team);
+    }
+
+    let our_team_line = teams
+        .iter()
+        .find(|team| team.team_id == our_team_id)
+        .and_then(|ours| {
+            let opponents: Vec<&str> = grouped
+                .get(&ours.next_tier)?
+                .iter()
+                .filter(|team| team.team_id != our_team_id)
+                .map(|team| team.team_name.as_str())
+                .collect();
+
+            Some(format!(
+                "{} will face {} in Tier {}",
+                ours.team_name,
+                opponents.join(" and "),
+                ours.next_tier
+            ))
+        })
+        .unwrap_or_default();
+
+    let next_matchups = grouped
+        .into_iter()
+        .map(|(tier, teams)| NextMatchup {
+            tier,
+            teams: teams.iter().map(|team| team.team_name.clone()).collect(),
+        })
+        .collect();
+
+    Prediction {
+        teams,
+        next_matchups,
+        our_team_line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::MatchColor;
+
+    const TOP: usize = 0;
+    const MIDDLE: usize = 2;
+    const BOTTOM: usize = 4;
+
+    fn team(name: &str, vp: u32) -> MatchColor {
+        MatchColor {
+            team_id: name.to_lowercase(),
+            team_name: name.to_string(),
+            victory_points: vp.to_string(),
+            ..MatchColor::default()
+        }
+    }
+
+    // Leader 100 VP, middle 90, last 80, with 30 skirmishes (60 VP of swing) left
+    fn predict(index: usize) -> Prediction {
+        let m = MatchData {
+            red: team("Leader", 100),
+            green: team("Middle", 90),
+            blue: team("Last", 80),
+            skirmishes_remaining: 30,
+        };
+        predict_movement(&BTreeMap::from([(index, m)]), "")
+    }
+
+    fn find<'a>(prediction: &'a Prediction, name: &str) -> &'a TeamPrediction {
+        prediction
+            .teams
+            .iter()
+            .find(|team| team.team_name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn top_tier() {
+        let prediction = predict(TOP);
+
+        // The leader can still fall to 3rd, it is 20 VP ahead of it
+        let leader = find(&prediction, "Leader");
+        assert_eq!(leader.movement, Movement::Stay);
+        assert_eq!(leader.vp_margin, Some(20));
+        assert_eq!(leader.confidence, Confidence::Contested);
+
+        let middle = find(&prediction, "Middle");
+        assert_eq!(middle.movement, Movement::Stay);
+        assert_eq!(middle.vp_margin, Some(10));
+
+        let last = find(&prediction, "Last");
+        assert_eq!(last.movement, Movement::Down);
+        assert_eq!(last.vp_margin, Some(10));
+        assert_eq!(last.next_tier, 2);
+    }
+
+    #[test]
+    fn middle_tier() {
+        let prediction = predict(MIDDLE);
+
+        let leader = find(&prediction, "Leader");
+        assert_eq!(leader.movement, Movement::Up);
+        assert_eq!(leader.vp_margin, Some(10));
+        assert_eq!(leader.next_tier, 2);
+
+        let middle = find(&prediction, "Middle");
+        assert_eq!(middle.movement, Movement::Stay);
+        assert_eq!(middle.vp_margin, Some(10));
+
+        let last = find(&prediction, "Last");
+        assert_eq!(last.movement, Movement::Down);
+        assert_eq!(last.vp_margin, Some(10));
+        assert_eq!(last.next_tier, 4);
+    }
+
+    #[test]
+    fn bottom_tier() {
+        let prediction = predict(BOTTOM);
+
+        let leader = find(&prediction, "Leader");
+        assert_eq!(leader.movement, Movement::Up);
+        assert_eq!(leader.vp_margin, Some(10));
+
+        let middle = find(&prediction, "Middle");
+        assert_eq!(middle.movement, Movement::Stay);
+        assert_eq!(middle.vp_margin, Some(10));
+
+        // Last can still move up by overtaking the leader
+        let last = find(&prediction, "Last");
+        assert_eq!(last.movement, Movement::Stay);
+        assert_eq!(last.vp_margin, Some(20));
+        assert_eq!(last.confidence, Confidence::Contested);
+    }
+
+    #[test]
+    fn margin_beyond_remaining_swing_is_locked() {
+        let m = MatchData {
+            red: team("Leader", 200),
+            green: team("Middle", 100),
+            blue: team("Last", 90),
+            skirmishes_remaining: 10,
+        };
+        let prediction = predict_movement(&BTreeMap::from([(TOP, m)]), "");
+
+        let leader = find(&prediction, "Leader");
+        assert_eq!(leader.vp_margin, Some(110));
+        assert_eq!(leader.confidence, Confidence::Locked);
+        assert_eq!(
+            find(&prediction, "Middle").confidence,
+            Confidence::Contested
+        );
+    }
+
+    #[test]
+    fn teams_are_told_apart_by_id() {
+        let m = MatchData {
+            red: MatchColor {
+                team_id: "11001".to_string(),
+                ..team("Unknown", 100)
+            },
+            green: MatchColor {
+                team_id: "11002".to_string(),
+                ..team("Unknown", 90)
+            },
+            blue: team("Last", 80),
+            skirmishes_remaining: 30,
+        };
+        let prediction = predict_movement(&BTreeMap::from([(TOP, m)]), "11001");
+
+        assert_eq!(
+            prediction.our_team_line,
+            "Unknown will face Unknown in Tier 1"
+        );
+        assert_eq!(prediction.next_matchups[0].teams, ["Unknown", "Unknown"]);
+    }
+}
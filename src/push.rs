@@ -0,0 +1,624 @@
+#![warn(clippy::pedantic)]
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    convert::Infallible,
+    io::{self, Write},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{
+        FromRef, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::{
+        Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use flate2::{
+    Compression,
+    write::{GzEncoder, ZlibEncoder},
+};
+use futures::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+
+use crate::{
+    data::{Data, MatchColor, MatchData, Objective},
+    tasks::log_error,
+};
+
+// Events a slow socket may fall behind before it is told it missed some
+pub const EVENT_CAPACITY: usize = 256;
+// Older versions a client can still receive a patch from
+const HISTORY_LEN: usize = 16;
+// Compression runs once per rebuild, so trade some speed for size
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 15;
+
+// One published state of the cache, serialized and compressed once and shared by every client
+#[derive(Default)]
+pub struct DataVersion {
+    pub etag: String,
+    // When the data last really changed, rebuilds with the same content keep the old version
+    pub modified: DateTime<Utc>,
+    pub json: Bytes,
+    // Compressed variants in order of preference
+    encoded: Vec<Variant>,
+    value: Arc<Value>,
+    // Newest first
+    history: VecDeque<(String, Arc<Value>)>,
+    // RFC 6902 patch from each version in the history to this one
+    patches: HashMap<String, Bytes>,
+}
+
+// One encoding of a version, each with its own entity tag so validators never cross encodings
+#[derive(Clone)]
+pub struct Variant {
+    // None for the plain JSON
+    pub encoding: Option<&'static str>,
+    pub etag: String,
+    pub body: Bytes,
+}
+
+impl DataVersion {
+    pub fn publish(json: Vec<u8>, etag: String, previous: &Self) -> Self {
+        let mut history = previous.history.clone();
+        if !previous.etag.is_empty() {
+            history.push_front((previous.etag.clone(), previous.value.clone()));
+            history.truncate(HISTORY_LEN);
+        }
+
+        let value: Value = serde_json::from_slice(&json).unwrap_or_default();
+        // Diffed once here instead of for every client asking for one
+        let patches = history
+            .iter()
+            .filter_map(|(old_etag, old)| {
+                serde_json::to_vec(&json_patch::diff(old, &value))
+                    .map_err(log_error)
+                    .ok()
+                    .map(|patch| (old_etag.clone(), Bytes::from(patch)))
+            })
+            .collect();
+        let json = Bytes::from(json);
+        let encoded = [
+            ("br", brotli(&json)),
+            ("zstd", zstd::encode_all(&*json, ZSTD_LEVEL)),
+            ("gzip", gzip(&json)),
+            ("deflate", deflate(&json)),
+        ]
+        .into_iter()
+        .filter_map(|(encoding, body)| match body {
+            Ok(body) => Some(Variant {
+                encoding: Some(encoding),
+                etag: variant_etag(&etag, encoding),
+                body: Bytes::from(body),
+            }),
+            Err(err) => {
+                log_error(err);
+                None
+            }
+        })
+        .collect();
+
+        Self {
+            etag,
+            modified: Utc::now(),
+            json,
+            encoded,
+            value: Arc::new(value),
+            history,
+            patches,
+        }
+    }
+
+    // IMF-fixdate as used by Last-Modified
+    pub fn last_modified(&self) -> String {
+        self.modified
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    // HTTP dates only have second precision
+    pub fn modified_since(&self, if_modified_since: &str) -> bool {
+        DateTime::parse_from_rfc2822(if_modified_since)
+            .map_or(true, |since| self.modified.timestamp() > since.timestamp())
+    }
+
+    // The best variant the client accepts, the plain JSON when it accepts none
+    pub fn variant(&self, accept_encoding: &str) -> Variant {
+        self.encoded
+            .iter()
+            .find(|variant| {
+                variant
+                    .encoding
+                    .is_some_and(|e| accepts(accept_encoding, e))
+            })
+            .cloned()
+            .unwrap_or_else(|| Variant {
+                encoding: None,
+                etag: self.etag.clone(),
+                body: self.json.clone(),
+            })
+    }
+
+    // RFC 6902 patch from an older version, None once that version left the history
+    pub fn patch_from(&self, etag: &str) -> Option<Bytes> {
+        self.patches.get(etag).cloned()
+    }
+}
+
+// Digest of the serialized bytes, stable across builds and instances
+pub fn content_etag(json: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(json))
+}
+
+// "<digest>" -> "<digest>-br"
+fn variant_etag(etag: &str, encoding: &str) -> String {
+    format!("{}-{encoding}\"", etag.trim_end_matches('"'))
+}
+
+// The version an entity tag was taken from, whatever encoding the client received
+pub fn version_etag(tag: &str) -> String {
+    let tag = tag.trim().trim_start_matches("W/");
+    tag.strip_suffix('"')
+        .and_then(|tag| tag.rsplit_once('-'))
+        .map_or_else(|| tag.to_string(), |(digest, _)| format!("{digest}\""))
+}
+
+// If-None-Match uses the weak comparison and may list several tags or "*"
+pub fn if_none_match(if_none_match: &str, etag: &str) -> bool {
+    entity_tags(if_none_match).any(|tag| tag == "*" || tag == etag)
+}
+
+pub fn entity_tags(if_none_match: &str) -> impl Iterator<Item = &str> {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .filter(|tag| !tag.is_empty())
+}
+
+fn brotli(json: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+    writer.write_all(json)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(out)
+}
+
+fn gzip(json: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(json)?;
+    encoder.finish()
+}
+
+fn deflate(json: &[u8]) -> io::Result<Vec<u8>> {
+    // HTTP deflate is the zlib format
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(json)?;
+    encoder.finish()
+}
+
+// Accept-Encoding contains the coding without q=0
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|part| {
+        let mut params = part.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        name.eq_ignore_ascii_case(encoding) && quality > 0.0
+    })
+}
+
+pub type Updates = watch::Receiver<Arc<DataVersion>>;
+pub type Events = broadcast::Sender<Arc<PushEvent>>;
+
+#[derive(Clone)]
+pub struct PushState {
+    pub updates: Updates,
+    pub events: Events,
+}
+
+impl FromRef<PushState> for Updates {
+    fn from_ref(state: &PushState) -> Self {
+        state.updates.clone()
+    }
+}
+
+impl FromRef<PushState> for Events {
+    fn from_ref(state: &PushState) -> Self {
+        state.events.clone()
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PushEvent {
+    Score {
+        tier: usize,
+        team_id: String,
+        team_name: String,
+        victory_points: u32,
+        points_per_tick: u32,
+    },
+    Roster {
+        tier: usize,
+        team_id: String,
+        joined: Vec<String>,
+        left: Vec<String>,
+    },
+    Flip {
+        tier: usize,
+        objective_id: String,
+        objective_type: String,
+        from: String,
+        to: String,
+        from_team_id: Option<String>,
+        to_team_id: Option<String>,
+        claimed_by: Option<String>,
+    },
+}
+
+impl PushEvent {
+    const fn tier(&self) -> usize {
+        match self {
+            Self::Score { tier, .. } | Self::Roster { tier, .. } | Self::Flip { tier, .. } => *tier,
+        }
+    }
+
+    fn involves_team(&self, teams: &BTreeSet<String>) -> bool {
+        match self {
+            Self::Score { team_id, .. } | Self::Roster { team_id, .. } => teams.contains(team_id),
+            Self::Flip {
+                from_team_id,
+                to_team_id,
+                ..
+            } => [from_team_id, to_team_id]
+                .into_iter()
+                .flatten()
+                .any(|id| teams.contains(id)),
+        }
+    }
+
+    // Rosters only know guild names, claims only guild ids, so either works as a topic
+    fn involves_guild(&self, guilds: &BTreeSet<String>) -> bool {
+        match self {
+            Self::Score { .. } => false,
+            Self::Roster { joined, left, .. } => {
+                joined.iter().chain(left).any(|name| guilds.contains(name))
+            }
+            Self::Flip { claimed_by, .. } => {
+                claimed_by.as_ref().is_some_and(|id| guilds.contains(id))
+            }
+        }
+    }
+}
+
+const fn colors(m: &MatchData) -> [(&'static str, &MatchColor); 3] {
+    [("Red", &m.red), ("Green", &m.green), ("Blue", &m.blue)]
+}
+
+fn roster(team: &MatchColor) -> BTreeSet<&String> {
+    team.guilds.values().flatten().collect()
+}
+
+// Score and roster changes between two builds of the cache
+pub fn diff_data(old: &Data, new: &Data) -> Vec<PushEvent> {
+    let mut events = Vec::new();
+
+    for (&index, m) in &new.matches {
+        let previous: HashMap<&str, &MatchColor> = old
+            .matches
+            .get(&index)
+            .map(|m| colors(m).map(|(_, team)| (team.team_id.as_str(), team)))
+            .into_iter()
+            .flatten()
+            .collect();
+
+        for (_, team) in colors(m) {
+            let before = previous.get(team.team_id.as_str());
+
+            if before.is_none_or(|b| {
+                b.victory_points != team.victory_points || b.points_per_tick != team.points_per_tick
+            }) {
+                events.push(PushEvent::Score {
+                    tier: index + 1,
+                    team_id: team.team_id.clone(),
+                    team_name: team.team_name.clone(),
+                    victory_points: team.victory_points.parse().unwrap_or(0),
+                    points_per_tick: team.points_per_tick,
+                });
+            }
+
+            let Some(before) = before else {
+                continue;
+            };
+            let (old_roster, new_roster) = (roster(before), roster(team));
+            if old_roster != new_roster {
+                events.push(PushEvent::Roster {
+                    tier: index + 1,
+                    team_id: team.team_id.clone(),
+                    joined: new_roster
+                        .difference(&old_roster)
+                        .map(|name| (*name).clone())
+                        .collect(),
+                    left: old_roster
+                        .difference(&new_roster)
+                        .map(|name| (*name).clone())
+                        .collect(),
+                });
+            }
+        }
+    }
+
+    events
+}
+
+// Owner changes of the objectives in one tier
+pub fn diff_objectives(
+    tier: usize,
+    m: Option<&MatchData>,
+    old: &[Objective],
+    new: &[Objective],
+) -> Vec<PushEvent> {
+    let team_id = |owner: &str| {
+        m.and_then(|m| {
+            colors(m)
+                .into_iter()
+                .find(|(color, _)| *color == owner)
+                .map(|(_, team)| team.team_id.clone())
+        })
+    };
+
+    let previous: HashMap<&str, &Objective> = old.iter().map(|o| (o.id.as_str(), o)).collect();
+
+    new.iter()
+        .filter_map(|objective| {
+            let before = previous.get(objective.id.as_str())?;
+            (before.owner != objective.owner).then(|| PushEvent::Flip {
+                tier,
+                objective_id: objective.id.clone(),
+                objective_type: objective.kind.clone(),
+                from_team_id: team_id(&before.owner),
+                to_team_id: team_id(&objective.owner),
+                from: before.owner.clone(),
+                to: objective.owner.clone(),
+                claimed_by: objective.claimed_by.clone(),
+            })
+        })
+        .collect()
+}
+
+// Sends the current version unless the client already has it (Last-Event-ID), then every new one
+pub async fn data_events(
+    State(updates): State<Updates>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string);
+
+    let events = stream::unfold((updates, last_id), |(mut updates, last_id)| async move {
+        loop {
+            let version = updates.borrow_and_update().clone();
+            if !version.etag.is_empty() && last_id.as_deref() != Some(version.etag.as_str()) {
+                let json = std::str::from_utf8(&version.json).unwrap_or_default();
+                let event = Event::default().id(&version.etag).data(json);
+                return Some((Ok(event), (updates, Some(version.etag.clone()))));
+            }
+            updates.changed().await.ok()?;
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+struct Topics {
+    #[serde(default)]
+    tiers: BTreeSet<usize>,
+    #[serde(default)]
+    teams: BTreeSet<String>,
+    #[serde(default)]
+    guilds: BTreeSet<String>,
+}
+
+impl Topics {
+    fn matches(&self, event: &PushEvent) -> bool {
+        self.tiers.contains(&event.tier())
+            || event.involves_team(&self.teams)
+            || event.involves_guild(&self.guilds)
+    }
+}
+
+// {"action": "subscribe", "tiers": [1], "teams": ["11001"], "guilds": ["<id or name>"]}
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe(Topics),
+    Unsubscribe(Topics),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed(Topics),
+    Lagged { skipped: u64 },
+    Error { message: String },
+}
+
+pub async fn ws(ws: WebSocketUpgrade, State(events): State<Events>) -> Response {
+    let events = events.subscribe();
+    ws.on_upgrade(move |socket| handle_socket(socket, events))
+}
+
+async fn send_json<T: Serialize + Sync>(socket: &mut WebSocket, message: &T) -> bool {
+    let Ok(text) = serde_json::to_string(message) else {
+        return true;
+    };
+    socket.send(Message::text(text)).await.is_ok()
+}
+
+fn apply(topics: &mut Topics, text: &str) -> ServerMessage {
+    match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Subscribe(add)) => {
+            topics.tiers.extend(add.tiers);
+            topics.teams.extend(add.teams);
+            topics.guilds.extend(add.guilds);
+            ServerMessage::Subscribed(topics.clone())
+        }
+        Ok(ClientMessage::Unsubscribe(remove)) => {
+            topics.tiers.retain(|t| !remove.tiers.contains(t));
+            topics.teams.retain(|t| !remove.teams.contains(t));
+            topics.guilds.retain(|g| !remove.guilds.contains(g));
+            ServerMessage::Subscribed(topics.clone())
+        }
+        Err(err) => ServerMessage::Error {
+            message: err.to_string(),
+        },
+    }
+}
+
+async fn handle_socket(mut socket: WebSocket, mut events: broadcast::Receiver<Arc<PushEvent>>) {
+    // Nothing is delivered until the client subscribes to something
+    let mut topics = Topics::default();
+
+    loop {
+        let open = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = apply(&mut topics, text.as_str());
+                    send_json(&mut socket, &reply).await
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => false,
+                // Pings are answered by axum, binary frames are ignored
+                Some(Ok(_)) => true,
+            },
+            event = events.recv() => match event {
+                Ok(event) if topics.matches(&event) => send_json(&mut socket, &*event).await,
+                Ok(_) => true,
+                Err(RecvError::Lagged(skipped)) => {
+                    send_json(&mut socket, &ServerMessage::Lagged { skipped }).await
+                }
+                Err(RecvError::Closed) => false,
+            },
+        };
+
+        if !open {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(previous: &DataVersion, value: &Value) -> DataVersion {
+        let json = serde_json::to_vec(value).unwrap();
+        let etag = content_etag(&json);
+        DataVersion::publish(json, etag, previous)
+    }
+
+    #[test]
+    fn patches_lead_from_every_retained_version_to_the_newest() {
+        let values = [
+            serde_json::json!({ "tier": 1, "teams": ["a", "b"] }),
+            serde_json::json!({ "tier": 1, "teams": ["a", "c"] }),
+            serde_json::json!({ "tier": 2, "teams": ["c"], "extra": true }),
+        ];
+        let first = publish(&DataVersion::default(), &values[0]);
+        let second = publish(&first, &values[1]);
+        let third = publish(&second, &values[2]);
+
+        for (old, old_value) in [(&first, &values[0]), (&second, &values[1])] {
+            let patch: json_patch::Patch =
+                serde_json::from_slice(&third.patch_from(&old.etag).unwrap()).unwrap();
+            let mut patched = old_value.clone();
+            json_patch::patch(&mut patched, &patch).unwrap();
+            assert_eq!(patched, values[2]);
+        }
+
+        assert!(third.patch_from(&third.etag).is_none());
+        assert!(third.patch_from("\"unknown\"").is_none());
+        assert!(first.patch_from(&first.etag).is_none());
+    }
+
+    #[test]
+    fn old_versions_fall_out_of_the_history() {
+        let mut versions = vec![publish(&DataVersion::default(), &serde_json::json!(0))];
+        for i in 1..=HISTORY_LEN + 1 {
+            let next = publish(versions.last().unwrap(), &serde_json::json!(i));
+            versions.push(next);
+        }
+        let newest = versions.last().unwrap();
+
+        assert!(newest.patch_from(&versions[0].etag).is_none());
+        assert!(newest.patch_from(&versions[1].etag).is_some());
+        assert_eq!(newest.patches.len(), HISTORY_LEN);
+    }
+
+    #[test]
+    fn accepts_honors_quality_and_case() {
+        assert!(accepts("gzip, deflate, br", "br"));
+        assert!(accepts("GZIP;q=0.5", "gzip"));
+        assert!(!accepts("br;q=0, gzip", "br"));
+        assert!(!accepts("br;q=0.0", "br"));
+        assert!(!accepts("gzip", "br"));
+        assert!(!accepts("", "gzip"));
+    }
+
+    #[test]
+    fn each_encoding_gets_its_own_etag() {
+        let version = publish(&DataVersion::default(), &serde_json::json!({ "tier": 1 }));
+
+        let br = version.variant("gzip, br");
+        assert_eq!(br.encoding, Some("br"));
+        assert_eq!(br.etag, variant_etag(&version.etag, "br"));
+
+        let gzip = version.variant("gzip, br;q=0");
+        assert_eq!(gzip.encoding, Some("gzip"));
+
+        let plain = version.variant("identity");
+        assert_eq!(plain.encoding, None);
+        assert_eq!(plain.etag, version.etag);
+        assert_eq!(plain.body, version.json);
+
+        let etags: BTreeSet<_> = ["br", "zstd", "gzip", "deflate", "identity"]
+            .into_iter()
+            .map(|encoding| version.variant(encoding).etag)
+            .collect();
+        assert_eq!(etags.len(), 5);
+        for etag in &etags {
+            assert_eq!(version_etag(etag), version.etag);
+        }
+    }
+
+    #[test]
+    fn if_none_match_lists() {
+        assert!(if_none_match("\"a-br\"", "\"a-br\""));
+        assert!(if_none_match("\"b\", W/\"a-br\"", "\"a-br\""));
+        assert!(if_none_match("*", "\"a\""));
+        assert!(!if_none_match("\"a\"", "\"a-br\""));
+        assert!(!if_none_match("\"a-gzip\"", "\"a-br\""));
+        assert_eq!(
+            entity_tags(" \"a\" , ,W/\"b\"").collect::<Vec<_>>(),
+            ["\"a\"", "\"b\""]
+        );
+    }
+}
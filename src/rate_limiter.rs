@@ -5,6 +5,8 @@ use std::{
     time::SystemTime,
 };
 
+use reqwest::StatusCode;
+
 use serde::de::DeserializeOwned;
 use tokio::{sync::oneshot, time};
 
@@ -17,6 +19,12 @@ pub enum Priority {
     Low,
 }
 
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound,
+    Failed(String),
+}
+
 struct ApiCall {
     priority: Priority,
     enqueue_time: SystemTime,
@@ -91,15 +99,50 @@ impl ApiQueue {
     where
         T: 'static + Send + DeserializeOwned,
     {
-        let (tx, rx) = oneshot::channel::<Option<T>>();
+        let result = self.enqueue_checked::<T>(end_point, priority);
+        async move { result.await.ok() }
+    }
+
+    pub fn enqueue_checked<T>(
+        &self,
+        end_point: &APIEndpoint,
+        priority: Priority,
+    ) -> impl Future<Output = Result<T, ApiError>>
+    where
+        T: 'static + Send + DeserializeOwned,
+    {
+        let bytes = self.enqueue_bytes(end_point, priority);
+        async move {
+            bytes.await.and_then(|bytes| {
+                serde_json::from_slice::<T>(&bytes).map_err(|e| ApiError::Failed(e.to_string()))
+            })
+        }
+    }
+
+    pub fn enqueue_bytes(
+        &self,
+        end_point: &APIEndpoint,
+        priority: Priority,
+    ) -> impl Future<Output = Result<Vec<u8>, ApiError>> {
+        let (tx, rx) = oneshot::channel::<Result<Vec<u8>, ApiError>>();
         let url_clone = end_point.to_string();
         let job = move || {
             tokio::spawn(async move {
-                if let Ok(response) = reqwest::get(url_clone).await
-                    && let Ok(t) = response.json::<T>().await
-                {
-                    let _ = tx.send(Some(t));
-                }
+                let result = match reqwest::get(url_clone).await {
+                    Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                        Err(ApiError::NotFound)
+                    }
+                    Ok(response) => match response.error_for_status() {
+                        Ok(response) => response
+                            .bytes()
+                            .await
+                            .map(|bytes| bytes.to_vec())
+                            .map_err(|e| ApiError::Failed(e.to_string())),
+                        Err(e) => Err(ApiError::Failed(e.to_string())),
+                    },
+                    Err(e) => Err(ApiError::Failed(e.to_string())),
+                };
+                let _ = tx.send(result);
             })
         };
 
@@ -111,7 +154,10 @@ impl ApiQueue {
 
         self.queue.lock().unwrap().push(call);
 
-        async move { rx.await.unwrap_or(None) }
+        async move {
+            rx.await
+                .unwrap_or_else(|_| Err(ApiError::Failed("request dropped".to_string())))
+        }
     }
 
     fn start_queue(&self) {
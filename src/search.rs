@@ -0,0 +1,332 @@
+#![warn(clippy::pedantic)]
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use sqlx::SqlitePool;
+use strsim::damerau_levenshtein;
+
+use crate::{
+    data::{Data, Guild, GuildSearchResult},
+    database::{get_former_guild_names, get_guilds_with_teams},
+    tasks::{TEAM_NAMES, normalize_name},
+};
+
+const EXACT: u32 = 100;
+const TAG_EXACT: u32 = 90;
+const PREFIX: u32 = 80;
+const WORD_PREFIX: u32 = 70;
+const TAG_PREFIX: u32 = 65;
+const CONTAINS: u32 = 60;
+const TYPO: u32 = 50;
+const TYPO_PENALTY: u32 = 10;
+const FORMER_PENALTY: u32 = 5;
+
+fn typo_distance(query: &str, name: &str) -> usize {
+    let query_len = query.chars().count();
+    let prefix: String = name.chars().take(query_len).collect();
+
+    // Compare against the whole name, its leading part and every word so partial input still counts
+    std::iter::once(name)
+        .chain(std::iter::once(prefix.as_str()))
+        .chain(name.split_whitespace())
+        .map(|candidate| damerau_levenshtein(query, candidate))
+        .min()
+        .unwrap_or(usize::MAX)
+}
+
+// Both sides are already normalized
+fn name_score(query: &str, name: &str) -> Option<u32> {
+    if name == query {
+        return Some(EXACT);
+    }
+    if name.starts_with(query) {
+        return Some(PREFIX);
+    }
+    if name.split_whitespace().any(|word| word.starts_with(query)) {
+        return Some(WORD_PREFIX);
+    }
+    if name.contains(query) {
+        return Some(CONTAINS);
+    }
+
+    let allowed = (query.chars().count() / 4).max(1);
+    let distance = typo_distance(query, name);
+    (distance <= allowed).then(|| {
+        TYPO.saturating_sub(
+            u32::try_from(distance)
+                .unwrap_or(u32::MAX)
+                .saturating_mul(TYPO_PENALTY),
+        )
+    })
+}
+
+fn tag_score(query: &str, tag: &str) -> Option<u32> {
+    if tag.is_empty() {
+        None
+    } else if tag == query {
+        Some(TAG_EXACT)
+    } else if tag.starts_with(query) {
+        Some(TAG_PREFIX)
+    } else {
+        None
+    }
+}
+
+// team id -> (tier number, color) for the matches currently in the cache
+pub fn team_locations(data: &Data) -> HashMap<&str, (usize, &'static str)> {
+    let mut locations = HashMap::new();
+    for (index, m) in &data.matches {
+        for (color, team) in [("red", &m.red), ("green", &m.green), ("blue", &m.blue)] {
+            locations.insert(team.team_id.as_str(), (index + 1, color));
+        }
+    }
+    locations
+}
+
+struct IndexedGuild {
+    guild: Guild,
+    team_id: Option<String>,
+    name: String,
+    tag: String,
+    // (normalized, as it was shown)
+    former_names: Vec<(String, String)>,
+    former_tags: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy)]
+enum Former<'a> {
+    Name(&'a String),
+    Tag(&'a String),
+}
+
+// Distinct old values next to their normalized form, leaving out the current one
+fn former_values<'a>(
+    values: impl Iterator<Item = &'a String>,
+    current: &str,
+) -> Vec<(String, String)> {
+    let mut values: Vec<_> = values
+        .filter(|value| *value != current)
+        .map(|value| (normalize_name(value), value.clone()))
+        .collect();
+    values.sort();
+    values.dedup();
+    values
+}
+
+// Guilds with their names normalized up front, rebuilt with the cache instead of per search
+#[derive(Default)]
+pub struct SearchIndex {
+    guilds: Vec<IndexedGuild>,
+}
+
+impl SearchIndex {
+    pub async fn build(pool: &SqlitePool) -> Result<Self, sqlx::Error> {
+        let guilds = get_guilds_with_teams(pool).await?;
+
+        let mut history: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for (guild_id, name, tag) in get_former_guild_names(pool).await? {
+            history.entry(guild_id).or_default().push((name, tag));
+        }
+
+        Ok(Self::new(guilds, history))
+    }
+
+    // history maps guild id -> (name, tag) pairs the guild has gone by
+    pub fn new(
+        guilds: Vec<(Guild, Option<String>)>,
+        mut history: HashMap<String, Vec<(String, String)>>,
+    ) -> Self {
+        Self {
+            guilds: guilds
+                .into_iter()
+                .map(|(guild, team_id)| {
+                    let history = history.remove(&guild.id).unwrap_or_default();
+                    IndexedGuild {
+                        name: normalize_name(&guild.name),
+                        tag: normalize_name(&guild.tag),
+                        former_names: former_values(
+                            history.iter().map(|(name, _)| name),
+                            &guild.name,
+                        ),
+                        former_tags: former_values(history.iter().map(|(_, tag)| tag), &guild.tag),
+                        team_id,
+                        guild,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn search(&self, query: &str, data: &Data, limit: usize) -> Vec<GuildSearchResult> {
+        let query = normalize_name(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches = Vec::new();
+
+        for indexed in &self.guilds {
+            let current = [
+                name_score(&query, &indexed.name),
+                tag_score(&query, &indexed.tag),
+            ]
+            .into_iter()
+            .flatten()
+            .max();
+
+            let former_names = indexed
+                .former_names
+                .iter()
+                .filter_map(|(normalized, name)| {
+                    name_score(&query, normalized).map(|score| (score, Former::Name(name)))
+                });
+            let former_tags = indexed.former_tags.iter().filter_map(|(normalized, tag)| {
+                tag_score(&query, normalized).map(|score| (score, Former::Tag(tag)))
+            });
+            let former = former_names
+                .chain(former_tags)
+                .map(|(score, value)| (score.saturating_sub(FORMER_PENALTY), value))
+                .max_by_key(|(score, _)| *score);
+
+            let (score, former) = match (current, former) {
+                (Some(score), Some((former_score, value))) if former_score > score => {
+                    (former_score, Some(value))
+                }
+                (Some(score), _) => (score, None),
+                (None, Some((former_score, value))) => (former_score, Some(value)),
+                (None, None) => continue,
+            };
+
+            matches.push((score, former, indexed));
+        }
+
+        matches.sort_by(|(a_score, _, a), (b_score, _, b)| {
+            (Reverse(a_score), &a.name).cmp(&(Reverse(b_score), &b.name))
+        });
+        matches.truncate(limit);
+
+        let locations = team_locations(data);
+        matches
+            .into_iter()
+            .map(|(score, former, indexed)| {
+                let team_id = indexed.team_id.clone();
+                let location = team_id.as_deref().and_then(|id| locations.get(id)).copied();
+
+                GuildSearchResult {
+                    team_name: team_id
+                        .as_deref()
+                        .and_then(|id| TEAM_NAMES.get(id))
+                        .map(|name| (*name).to_string()),
+                    id: indexed.guild.id.clone(),
+                    name: indexed.guild.name.clone(),
+                    tag: indexed.guild.tag.clone(),
+                    former_name: match former {
+                        Some(Former::Name(name)) => Some(name.clone()),
+                        _ => None,
+                    },
+                    former_tag: match former {
+                        Some(Former::Tag(tag)) => Some(tag.clone()),
+                        _ => None,
+                    },
+                    team_id,
+                    tier: location.map(|(tier, _)| tier),
+                    color: location.map(|(_, color)| color.to_string()),
+                    score,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guild(id: &str, name: &str, tag: &str) -> (Guild, Option<String>) {
+        (
+            Guild {
+                id: id.to_string(),
+                name: name.to_string(),
+                tag: tag.to_string(),
+                emblem: None,
+            },
+            None,
+        )
+    }
+
+    fn index() -> SearchIndex {
+        SearchIndex::new(
+            vec![
+                guild("1", "Dragon Brigade", "DB"),
+                guild("2", "Drakkar Riders", "DRAK"),
+                guild("3", "Sôlace of the Night", "SOL"),
+                guild("4", "Red Dragons", "RD"),
+                guild("5", "Quiet Hours", "QH"),
+            ],
+            HashMap::from([(
+                "5".to_string(),
+                vec![("Loud Minutes".to_string(), "LOUD".to_string())],
+            )]),
+        )
+    }
+
+    fn search(query: &str) -> Vec<(String, u32)> {
+        index()
+            .search(query, &Data::default(), 10)
+            .into_iter()
+            .map(|r| (r.id, r.score))
+            .collect()
+    }
+
+    #[test]
+    fn score_order() {
+        assert_eq!(name_score("dragon brigade", "dragon brigade"), Some(EXACT));
+        assert_eq!(name_score("dragon", "dragon brigade"), Some(PREFIX));
+        assert_eq!(name_score("brig", "dragon brigade"), Some(WORD_PREFIX));
+        assert_eq!(name_score("agon", "dragon brigade"), Some(CONTAINS));
+        assert_eq!(
+            name_score("dragno", "dragon brigade"),
+            Some(TYPO - TYPO_PENALTY)
+        );
+        assert_eq!(name_score("zebra", "dragon brigade"), None);
+        assert_eq!(tag_score("db", "db"), Some(TAG_EXACT));
+        assert_eq!(tag_score("dr", "drak"), Some(TAG_PREFIX));
+        assert_eq!(tag_score("db", ""), None);
+    }
+
+    #[test]
+    fn best_match_first() {
+        let results = search("dragon");
+        assert_eq!(results[0], ("1".to_string(), PREFIX));
+        assert_eq!(results[1], ("4".to_string(), WORD_PREFIX));
+    }
+
+    #[test]
+    fn accents_and_case_are_ignored() {
+        assert_eq!(search("SOLACE")[0], ("3".to_string(), PREFIX));
+        assert_eq!(search("sol")[0].0, "3");
+    }
+
+    #[test]
+    fn former_names_are_found_with_a_penalty() {
+        let results = index().search("loud minutes", &Data::default(), 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, "5");
+        assert_eq!(results[0].former_name.as_deref(), Some("Loud Minutes"));
+        assert_eq!(results[0].former_tag, None);
+        assert_eq!(results[0].score, EXACT - FORMER_PENALTY);
+
+        let results = index().search("loud", &Data::default(), 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, "5");
+        assert_eq!(results[0].former_tag.as_deref(), Some("LOUD"));
+        assert_eq!(results[0].former_name, None);
+        assert_eq!(results[0].score, TAG_EXACT - FORMER_PENALTY);
+    }
+
+    #[test]
+    fn limit_and_empty_query() {
+        assert_eq!(index().search("d", &Data::default(), 2).len(), 2);
+        assert!(search("   ").is_empty());
+    }
+}
@@ -10,26 +10,38 @@ use std::{
     sync::Arc,
 };
 
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, stream::FuturesUnordered};
 use phf::phf_map;
 use sqlx::SqlitePool;
 use tokio::{
-    sync::RwLock,
+    sync::{RwLock, watch},
     time::{self},
 };
 use unicode_normalization::UnicodeNormalization;
 use unicode_normalization::char::is_combining_mark;
 
 use crate::{
-    data::{APIEndpoint, Data, Guild, Match, MatchColor, MatchData, Tier},
+    data::{
+        APIEndpoint, ArchivedMatch, ArchivedTeam, Data, Guild, GuildMove, Match, MatchColor,
+        MatchData, MoveKind, Objective, Scores, Tier,
+    },
     database::{
-        get_guilds_for_team, get_match, get_team_id_for_guild, guild_in_db, guilds_to_update,
-        upsert_guild, upsert_guild_team_null, upsert_guild_teams_bulk, upsert_match,
+        archive_match, archive_roster, get_all_guild_teams, get_current_skirmish,
+        get_guilds_for_team, get_guilds_for_team_at, get_match, get_objectives,
+        get_team_id_for_guild, guild_fetch_allowed, guild_in_db, guilds_to_update,
+        insert_guild_moves, insert_snapshot, match_archived, prune_snapshots, record_guild_failure,
+        subscribe_changes, upsert_current_skirmish, upsert_guild, upsert_guild_team_null,
+        upsert_guild_teams_bulk, upsert_match, upsert_objective_claims, upsert_objectives,
     },
+    emblem::{EmblemError, store_emblem_parts},
+    prediction::predict_movement,
+    push::{DataVersion, Events, content_etag, diff_data, diff_objectives},
     rate_limiter::{ApiQueue, Priority},
+    search::SearchIndex,
 };
 
-static TEAM_NAMES: phf::Map<&'static str, &'static str> = phf_map! {
+pub static TEAM_NAMES: phf::Map<&'static str, &'static str> = phf_map! {
     "11001" => "Moogooloo",
     "11002" => "Rall's Rest",
     "11003" => "Domain of Torment",
@@ -109,7 +121,7 @@ pub fn start_update_loops(pool: &SqlitePool, api_queue: &Arc<ApiQueue>) {
 }
 
 pub async fn update_matches(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
-    let mut interval = time::interval(tokio::time::Duration::from_secs(60));
+    let mut interval = time::interval(tokio::time::Duration::from_mins(1));
 
     loop {
         interval.tick().await;
@@ -121,11 +133,34 @@ pub async fn update_matches(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
             let api_queue = api_queue.clone();
 
             tasks.push(async move {
+                // Archive before the next week overwrites the row under the same id
+                if let Some(previous) = get_match(&pool, tier).await
+                    && previous
+                        .end_time
+                        .parse::<DateTime<Utc>>()
+                        .is_ok_and(|end| end <= Utc::now())
+                {
+                    archive_finished_match(&pool, &previous).await;
+                }
+
                 if let Some(m) = api_queue
                     .enqueue::<Match>(&APIEndpoint::Match(tier), Priority::High)
                     .await
                 {
                     upsert_match(&pool, &m).await;
+                    insert_snapshot(&pool, &m, Utc::now()).await;
+                    if let Ok(start) = m.start_time.parse::<DateTime<Utc>>() {
+                        prune_snapshots(&pool, &m.id, start).await;
+                    }
+
+                    let objectives: Vec<&Objective> =
+                        m.maps.iter().flat_map(|map| &map.objectives).collect();
+                    upsert_objectives(&pool, &m.id, &objectives).await;
+                    upsert_objective_claims(&pool, &m.id, &objectives).await;
+
+                    if let Some(skirmish) = m.skirmishes.iter().max_by_key(|s| s.id) {
+                        upsert_current_skirmish(&pool, &m.id, skirmish).await;
+                    }
                 }
             });
         }
@@ -134,6 +169,69 @@ pub async fn update_matches(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
     }
 }
 
+fn week_key(m: &Match) -> String {
+    let start_date = m.start_time.get(..10).unwrap_or(&m.start_time);
+    format!("{start_date}_{}", m.id)
+}
+
+async fn archive_finished_match(pool: &SqlitePool, m: &Match) {
+    let week_key = week_key(m);
+    if match_archived(pool, &week_key).await {
+        return;
+    }
+
+    let ids = [
+        fix_team_ids(&m.worlds.red.to_string()),
+        fix_team_ids(&m.worlds.green.to_string()),
+        fix_team_ids(&m.worlds.blue.to_string()),
+    ];
+    let vp = [
+        m.victory_points.red,
+        m.victory_points.green,
+        m.victory_points.blue,
+    ];
+    let placement = placements(vp);
+
+    // update_teams may have applied next week's relink by now
+    let end_time = match m.end_time.parse::<DateTime<Utc>>() {
+        Ok(end_time) => end_time,
+        Err(err) => {
+            log_error(err);
+            return;
+        }
+    };
+
+    for team_id in &ids {
+        match get_guilds_for_team_at(pool, team_id, end_time).await {
+            Ok(guilds) => archive_roster(pool, &week_key, team_id, &guilds).await,
+            Err(err) => log_error(err),
+        }
+    }
+
+    let [red, green, blue] = [0, 1, 2].map(|i| ArchivedTeam {
+        team_id: ids[i].clone(),
+        team_name: TEAM_NAMES
+            .get(&ids[i])
+            .map_or_else(|| "Unknown".to_string(), |name| (*name).to_string()),
+        victory_points: vp[i],
+        placement: placement[i],
+    });
+
+    archive_match(
+        pool,
+        &ArchivedMatch {
+            week_key,
+            match_id: m.id.clone(),
+            start_time: m.start_time.clone(),
+            end_time: m.end_time.clone(),
+            red,
+            green,
+            blue,
+        },
+    )
+    .await;
+}
+
 fn sort_guilds(
     unsorted_guilds: HashMap<String, String>,
     my_guild_id: &str,
@@ -164,8 +262,23 @@ fn sort_guilds(
     my_guild_group
 }
 
+// The emblem endpoint only composes stored parts, so they are fetched along with the guild
+async fn store_guild(pool: &SqlitePool, api_queue: &ApiQueue, guild: Guild) {
+    let emblem = guild.emblem.clone();
+    upsert_guild(pool, guild).await;
+
+    let Some(emblem) = emblem else {
+        return;
+    };
+    match store_emblem_parts(pool, api_queue, &emblem).await {
+        Ok(()) => {}
+        Err(EmblemError::Database(err)) => log_error(err),
+        Err(EmblemError::Api(err)) => log_error(err),
+    }
+}
+
 pub async fn update_known_guilds(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
-    let mut interval = time::interval(tokio::time::Duration::from_secs(60));
+    let mut interval = time::interval(tokio::time::Duration::from_mins(1));
 
     loop {
         interval.tick().await;
@@ -175,12 +288,14 @@ pub async fn update_known_guilds(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
         let mut tasks = FuturesUnordered::new();
 
         for id in guild_ids {
-            tasks.push(async {
-                if let Some(guild) = api_queue
-                    .enqueue::<Guild>(&APIEndpoint::Guild(id), Priority::Low)
+            let api_queue = &api_queue;
+            tasks.push(async move {
+                match api_queue
+                    .enqueue_checked::<Guild>(&APIEndpoint::Guild(id.clone()), Priority::Low)
                     .await
                 {
-                    upsert_guild(pool, guild).await;
+                    Ok(guild) => store_guild(pool, api_queue, guild).await,
+                    Err(err) => record_guild_failure(pool, &id, &err).await,
                 }
             });
         }
@@ -189,8 +304,49 @@ pub async fn update_known_guilds(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
     }
 }
 
+fn diff_guild_moves(
+    previous: &HashMap<String, String>,
+    current: &HashMap<String, String>,
+    occurred_at: &str,
+) -> Vec<GuildMove> {
+    let mut moves = Vec::new();
+
+    for (guild_id, to_team) in current {
+        let from_team = previous.get(guild_id);
+        let kind = match from_team {
+            None => MoveKind::Joined,
+            Some(from_team) if from_team != to_team => MoveKind::Switched,
+            Some(_) => continue,
+        };
+
+        moves.push(GuildMove {
+            guild_id: guild_id.clone(),
+            guild_name: None,
+            kind,
+            from_team: from_team.cloned(),
+            to_team: Some(to_team.clone()),
+            occurred_at: occurred_at.to_string(),
+        });
+    }
+
+    for (guild_id, from_team) in previous {
+        if !current.contains_key(guild_id) {
+            moves.push(GuildMove {
+                guild_id: guild_id.clone(),
+                guild_name: None,
+                kind: MoveKind::Left,
+                from_team: Some(from_team.clone()),
+                to_team: None,
+                occurred_at: occurred_at.to_string(),
+            });
+        }
+    }
+
+    moves
+}
+
 pub async fn update_teams(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
-    let mut interval = time::interval(tokio::time::Duration::from_secs(60));
+    let mut interval = time::interval(tokio::time::Duration::from_mins(1));
 
     loop {
         interval.tick().await;
@@ -199,6 +355,23 @@ pub async fn update_teams(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
             .enqueue::<HashMap<String, String>>(&APIEndpoint::AllWvWGuilds, Priority::High)
             .await
         {
+            match get_all_guild_teams(pool).await {
+                Ok(previous) => {
+                    let previous: HashMap<String, String> = previous
+                        .into_iter()
+                        .filter_map(|(guild_id, team_id)| Some((guild_id, team_id?)))
+                        .collect();
+
+                    // Nothing to compare against on the very first cycle
+                    if !previous.is_empty() {
+                        let moves =
+                            diff_guild_moves(&previous, &guild_map, &Utc::now().to_rfc3339());
+                        insert_guild_moves(pool, &moves).await;
+                    }
+                }
+                Err(err) => log_error(err),
+            }
+
             upsert_guild_team_null(pool, guild_map.keys().cloned().collect()).await;
 
             let guild_list = match api_queue
@@ -223,12 +396,17 @@ pub async fn update_teams(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
                 tasks.push(async move {
                     let exists: bool = guild_in_db(&pool, &guild_id).await;
 
-                    if !exists
-                        && let Some(guild) = api_queue
-                            .enqueue::<Guild>(&APIEndpoint::Guild(guild_id), Priority::Normal)
+                    if !exists && guild_fetch_allowed(&pool, &guild_id).await {
+                        match api_queue
+                            .enqueue_checked::<Guild>(
+                                &APIEndpoint::Guild(guild_id.clone()),
+                                Priority::Normal,
+                            )
                             .await
-                    {
-                        upsert_guild(&pool, guild).await;
+                        {
+                            Ok(guild) => store_guild(&pool, &api_queue, guild).await,
+                            Err(err) => record_guild_failure(&pool, &guild_id, &err).await,
+                        }
                     }
                 });
             }
@@ -238,7 +416,7 @@ pub async fn update_teams(pool: &SqlitePool, api_queue: Arc<ApiQueue>) {
     }
 }
 
-fn normalize_name(name: &str) -> String {
+pub fn normalize_name(name: &str) -> String {
     name.trim()
         .nfd()
         .filter(|c| c.is_ascii() || !is_combining_mark(*c))
@@ -263,7 +441,7 @@ fn group_guilds(guilds: Vec<String>) -> BTreeMap<char, Vec<String>> {
     grouped
 }
 
-fn fix_team_ids(s: &str) -> String {
+pub fn fix_team_ids(s: &str) -> String {
     let a = if s.len() == 4 {
         format!("1{s}")
     } else {
@@ -272,38 +450,162 @@ fn fix_team_ids(s: &str) -> String {
     if a == "12101" { "12015".to_owned() } else { a }
 }
 
-pub async fn run_mateches_cache_updater(pool: &SqlitePool, cache: Arc<RwLock<Data>>) {
-    let mut interval: time::Interval = time::interval(tokio::time::Duration::from_secs(1));
+const SKIRMISH_SECS: u32 = 2 * 60 * 60;
+const TICK_SECS: u32 = 5 * 60;
+// Victory points a finished skirmish awards to 1st, 2nd and 3rd place
+pub const SKIRMISH_VP: [u32; 3] = [5, 4, 3];
+
+fn remaining_ticks(start_time: &str, now: DateTime<Utc>) -> u32 {
+    let Ok(start) = start_time.parse::<DateTime<Utc>>() else {
+        return 0;
+    };
+
+    let elapsed = (now - start).num_seconds();
+    if elapsed < 0 {
+        return 0;
+    }
+
+    let remaining = i64::from(SKIRMISH_SECS) - elapsed % i64::from(SKIRMISH_SECS);
+    u32::try_from(remaining).map_or(0, |secs| secs.div_ceil(TICK_SECS))
+}
+
+fn skirmishes_remaining(end_time: &str, now: DateTime<Utc>) -> u32 {
+    end_time.parse::<DateTime<Utc>>().map_or(0, |end| {
+        u32::try_from((end - now).num_seconds()).map_or(0, |secs| secs.div_ceil(SKIRMISH_SECS))
+    })
+}
+
+fn points_per_tick(objectives: &[Objective]) -> [u32; 3] {
+    let mut ppt = [0; 3];
+    for o in objectives {
+        let color = match o.owner.as_str() {
+            "Red" => 0,
+            "Green" => 1,
+            "Blue" => 2,
+            _ => continue,
+        };
+        ppt[color] += o.points_per_tick();
+    }
+    ppt
+}
+
+fn placements(scores: [u32; 3]) -> [u8; 3] {
+    scores.map(|score| {
+        scores
+            .iter()
+            .filter(|&&other| other > score)
+            .fold(1, |placement, _| placement + 1)
+    })
+}
+
+// Writes come in bursts (one match update touches several tables), rebuild once they settle
+const REBUILD_DEBOUNCE: time::Duration = time::Duration::from_millis(250);
+// Guild refreshes write continuously, they still get picked up this often
+const REBUILD_MAX_DELAY: time::Duration = time::Duration::from_secs(2);
+// Skirmish countdowns and projections move with the clock even without writes
+const REBUILD_FALLBACK: time::Duration = time::Duration::from_mins(1);
+
+pub async fn run_mateches_cache_updater(
+    pool: &SqlitePool,
+    cache: Arc<RwLock<Data>>,
+    updates: watch::Sender<Arc<DataVersion>>,
+    events: Events,
+    search: Arc<RwLock<SearchIndex>>,
+) {
+    let mut writes = subscribe_changes();
+    // Build once right away
+    writes.mark_changed();
 
     let pool = pool.clone();
     tokio::spawn(async move {
+        let mut objectives: HashMap<usize, Vec<Objective>> = HashMap::new();
+
         loop {
-            interval.tick().await;
+            let _ = time::timeout(REBUILD_FALLBACK, writes.changed()).await;
+
+            let deadline = time::Instant::now() + REBUILD_MAX_DELAY;
+            while time::timeout_at(
+                deadline.min(time::Instant::now() + REBUILD_DEBOUNCE),
+                writes.changed(),
+            )
+            .await
+            .is_ok_and(|changed| changed.is_ok())
+            {}
+
             let data = build_data(&pool).await;
 
+            match SearchIndex::build(&pool).await {
+                Ok(index) => *search.write().await = index,
+                Err(err) => log_error(err),
+            }
+
+            let mut changes = Vec::new();
+            for (index, tier) in Tier::all().into_iter().enumerate() {
+                let current = get_objectives(&pool, tier).await;
+                if let Some(previous) = objectives.get(&index) {
+                    changes.extend(diff_objectives(
+                        index + 1,
+                        data.matches.get(&index),
+                        previous,
+                        &current,
+                    ));
+                }
+                objectives.insert(index, current);
+            }
+
+            // Only publish when something changed so push clients stay quiet otherwise
+            let json = serde_json::to_vec(&data).unwrap_or_default();
+            let etag = content_etag(&json);
+            let previous = updates.borrow().etag.clone();
+            if previous != etag && !previous.is_empty() {
+                changes.extend(diff_data(&*cache.read().await, &data));
+            }
+
+            // Sending only fails while no socket is connected
+            for change in changes {
+                let _ = events.send(Arc::new(change));
+            }
+
+            if previous == etag {
+                continue;
+            }
+
+            // Serializing and compressing is CPU bound, keep it off the other tasks' worker
+            let version =
+                tokio::task::block_in_place(|| DataVersion::publish(json, etag, &updates.borrow()));
+
             let mut write_guard = cache.write().await;
             *write_guard = data;
+            drop(write_guard);
+
+            updates.send_replace(Arc::new(version));
         }
     });
 }
 
 const IMPORTANT_GUILDS: &str = include_str!("../static/important_guilds.txt");
+// The guild whose team is `Data::our_team`
+pub const TRACKED_GUILD: &str = "Unga Bunga On Eh Bu Ga";
 
 pub async fn build_data(pool: &SqlitePool) -> Data {
-    let team_id: String = get_team_id_for_guild(pool, "Unga Bunga On Eh Bu Ga")
+    let team_id: String = get_team_id_for_guild(pool, TRACKED_GUILD)
         .await
         .ok()
         .flatten()
         .unwrap_or_else(|| "0".to_string());
+    let matches = build_all_matches(pool).await;
+    let our_team = TEAM_NAMES
+        .get(&team_id)
+        .map_or(String::new(), |name| (*name).to_string());
+
     Data {
-        matches: build_all_matches(pool).await,
+        prediction: predict_movement(&matches, &team_id),
+        matches,
         important_guilds: IMPORTANT_GUILDS
             .lines()
             .map(std::string::ToString::to_string)
             .collect(),
-        our_team: TEAM_NAMES
-            .get(&team_id)
-            .map_or(String::new(), |name| (*name).to_string()),
+        our_team,
     }
 }
 
@@ -324,14 +626,29 @@ pub async fn build_all_matches(pool: &SqlitePool) -> BTreeMap<usize, MatchData>
                 m.victory_points.blue,
             ];
 
+            let ppt = points_per_tick(&get_objectives(pool, tier).await);
+            let skirmish = get_current_skirmish(pool, tier)
+                .await
+                .map_or_else(Scores::default, |s| s.scores);
+            let skirmish = [skirmish.red, skirmish.green, skirmish.blue];
+
+            let ticks = remaining_ticks(&m.start_time, Utc::now());
+            let projected = [0, 1, 2].map(|i| skirmish[i] + ppt[i] * ticks);
+            let placement = placements(projected);
+
             let mut team = vec![];
 
             for i in 0..3 {
                 let t = MatchColor {
+                    team_id: ids[i].clone(),
                     team_name: TEAM_NAMES
                         .get(&ids[i])
                         .map_or_else(|| "Unknown".to_string(), |name| (*name).to_string()),
                     victory_points: vp[i].to_string(),
+                    points_per_tick: ppt[i],
+                    skirmish_score: skirmish[i],
+                    projected_score: projected[i],
+                    projected_placement: placement[i],
                     guilds: group_guilds(
                         get_guilds_for_team(pool, &ids[i])
                             .await
@@ -349,6 +666,7 @@ pub async fn build_all_matches(pool: &SqlitePool) -> BTreeMap<usize, MatchData>
                 red: team[0].clone(),
                 green: team[1].clone(),
                 blue: team[2].clone(),
+                skirmishes_remaining: skirmishes_remaining(&m.end_time, Utc::now()),
             };
 
             all_matches.insert(i, m);
@@ -356,3 +674,115 @@ pub async fn build_all_matches(pool: &SqlitePool) -> BTreeMap<usize, MatchData>
     }
     all_matches
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn objective(kind: &str, owner: &str, yaks_delivered: u32) -> Objective {
+        Objective {
+            id: String::new(),
+            kind: kind.to_string(),
+            owner: owner.to_string(),
+            last_flipped: None,
+            yaks_delivered,
+            claimed_by: None,
+            claimed_at: None,
+        }
+    }
+
+    fn teams(assignments: &[(&str, &str)]) -> HashMap<String, String> {
+        assignments
+            .iter()
+            .map(|(guild, team)| ((*guild).to_string(), (*team).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn guild_moves_between_cycles() {
+        let previous = teams(&[("stays", "A"), ("switches", "A"), ("leaves", "B")]);
+        let current = teams(&[("stays", "A"), ("switches", "B"), ("joins", "C")]);
+
+        let mut moves = diff_guild_moves(&previous, &current, "now");
+        moves.sort_by(|a, b| a.guild_id.cmp(&b.guild_id));
+
+        assert_eq!(moves.len(), 3);
+        assert_eq!(moves[0].guild_id, "joins");
+        assert!(matches!(moves[0].kind, MoveKind::Joined));
+        assert_eq!(moves[0].from_team, None);
+        assert_eq!(moves[0].to_team.as_deref(), Some("C"));
+
+        assert_eq!(moves[1].guild_id, "leaves");
+        assert!(matches!(moves[1].kind, MoveKind::Left));
+        assert_eq!(moves[1].from_team.as_deref(), Some("B"));
+        assert_eq!(moves[1].to_team, None);
+
+        assert_eq!(moves[2].guild_id, "switches");
+        assert!(matches!(moves[2].kind, MoveKind::Switched));
+        assert_eq!(moves[2].from_team.as_deref(), Some("A"));
+        assert_eq!(moves[2].to_team.as_deref(), Some("B"));
+        assert!(moves.iter().all(|m| m.occurred_at == "now"));
+    }
+
+    #[test]
+    fn no_guild_moves_without_changes() {
+        let assignments = teams(&[("a", "A"), ("b", "B")]);
+        assert!(diff_guild_moves(&assignments, &assignments, "now").is_empty());
+    }
+
+    #[test]
+    fn remaining_ticks_within_skirmish() {
+        let start = "2026-10-16T18:00:00Z";
+        assert_eq!(remaining_ticks(start, at("2026-10-16T18:00:00Z")), 24);
+        assert_eq!(remaining_ticks(start, at("2026-10-16T18:04:59Z")), 24);
+        assert_eq!(remaining_ticks(start, at("2026-10-16T18:05:00Z")), 23);
+        assert_eq!(remaining_ticks(start, at("2026-10-16T19:59:00Z")), 1);
+        // The next skirmish starts over
+        assert_eq!(remaining_ticks(start, at("2026-10-16T20:00:00Z")), 24);
+    }
+
+    #[test]
+    fn remaining_ticks_before_start_or_unparsable() {
+        assert_eq!(
+            remaining_ticks("2026-10-16T18:00:00Z", at("2026-10-16T17:00:00Z")),
+            0
+        );
+        assert_eq!(remaining_ticks("garbage", at("2026-10-16T17:00:00Z")), 0);
+    }
+
+    #[test]
+    fn skirmishes_remaining_rounds_up() {
+        let end = "2026-10-23T18:00:00Z";
+        assert_eq!(skirmishes_remaining(end, at("2026-10-16T18:00:00Z")), 84);
+        assert_eq!(skirmishes_remaining(end, at("2026-10-23T16:30:00Z")), 1);
+        assert_eq!(skirmishes_remaining(end, at("2026-10-23T18:00:01Z")), 0);
+    }
+
+    #[test]
+    fn points_per_tick_by_owner_and_upgrade() {
+        let objectives = [
+            objective("Camp", "Red", 0),
+            objective("Tower", "Red", 0),
+            objective("Castle", "Blue", 0),
+            objective("Keep", "Green", 1000),
+            objective("Spawn", "Green", 0),
+            objective("Camp", "Neutral", 0),
+        ];
+        let ppt = points_per_tick(&objectives);
+        assert_eq!(ppt[0], 6);
+        assert_eq!(ppt[1], 20);
+        assert_eq!(ppt[2], 12);
+    }
+
+    #[test]
+    fn placements_rank_highest_first() {
+        assert_eq!(placements([10, 30, 20]), [3, 1, 2]);
+        // Ties share the better placement
+        assert_eq!(placements([20, 20, 10]), [1, 1, 3]);
+        assert_eq!(placements([0, 0, 0]), [1, 1, 1]);
+    }
+}